@@ -51,13 +51,19 @@ extern crate cc;
 extern crate regex;
 
 use regex::Regex;
+use std::collections::HashMap;
 use std::env;
 use std::ffi::{OsStr, OsString};
+use std::fmt;
 use std::fs::{self, File};
 use std::io::prelude::*;
-use std::io::ErrorKind;
+use std::io::{self, ErrorKind};
+use std::ops::BitOr;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 
 /// Builder style configuration for a pending CMake build.
 pub struct Config {
@@ -66,7 +72,10 @@ pub struct Config {
     cflags: OsString,
     cxxflags: OsString,
     asmflags: OsString,
+    config_cflags: Vec<(String, OsString)>,
+    config_cxxflags: Vec<(String, OsString)>,
     defines: Vec<(OsString, OsString)>,
+    lazy_defines: Vec<(OsString, Box<dyn FnOnce() -> OsString + Send>)>,
     deps: Vec<String>,
     target: Option<String>,
     host: Option<String>,
@@ -76,11 +85,396 @@ pub struct Config {
     cmake_target: Option<String>,
     env: Vec<(OsString, OsString)>,
     static_crt: Option<bool>,
+    osx_sysroot: Option<String>,
     uses_cxx11: bool,
     always_configure: bool,
     no_build_target: bool,
     verbose_cmake: bool,
     verbose_make: bool,
+    cargo_metadata: bool,
+    graphviz: Option<PathBuf>,
+    env_cache: Mutex<HashMap<String, Option<OsString>>>,
+    required_cmake_version: Option<(u32, u32)>,
+    shared_libs_dir: Option<PathBuf>,
+    generated_toolchain_file: bool,
+    rerun_if_changed: bool,
+    rerun_excludes: Vec<PathBuf>,
+    preset: Option<String>,
+    presets_file: Option<PathBuf>,
+    whole_archive_libs: Vec<String>,
+    try_compile_static: bool,
+    link_args: Vec<String>,
+    verbosity: Verbosity,
+    prefix_path_files: Vec<PathBuf>,
+    export_compile_commands: bool,
+    no_warning_as_error: bool,
+    install_default_permissions: Option<String>,
+    find_debug: bool,
+    cc_flag_kinds: CcFlagKinds,
+    emit_timings: bool,
+    external_project_prefix: Option<PathBuf>,
+    target_triple_var: Option<String>,
+    build_target_sequence: Vec<String>,
+    probe_dir: Option<PathBuf>,
+    msvc_prefer_makefiles: bool,
+    warnings_as_errors: bool,
+    universal_archs: Vec<String>,
+    hidden_visibility: bool,
+    configure_timeout: Option<Duration>,
+    build_timeout: Option<Duration>,
+    relocatable_install: bool,
+    native_tool_args: Vec<(GeneratorKind, OsString)>,
+    metadata: Mutex<Vec<String>>,
+    linker: Option<PathBuf>,
+    rust_linker: bool,
+    pch: Option<bool>,
+    cargo_directives_file: Option<PathBuf>,
+    copy_source: bool,
+    parallel: Option<u32>,
+    cross_defaults: bool,
+    env_clear: bool,
+    sysroot_search_paths: bool,
+    ldflags: OsString,
+    verbose_build: bool,
+    lto: bool,
+    bootstrap: Option<Command>,
+    prune_stale_installs: bool,
+    pic: Option<bool>,
+    build_all_targets: bool,
+    message_context: bool,
+    header_only: bool,
+    capture_output: bool,
+    root_metadata_path: Option<PathBuf>,
+    existing_build_dir: Option<PathBuf>,
+    osx_deployment_target: Option<String>,
+    android_ndk: Option<PathBuf>,
+    android_api_level: Option<u32>,
+    build_report: Option<PathBuf>,
+    build_dir_name: PathBuf,
+    musl_static: Option<bool>,
+    musl_sysroot: Option<PathBuf>,
+    no_default_compiler: bool,
+    max_load: Option<f32>,
+    compiler_launcher: Option<OsString>,
+    clean_first: bool,
+    forced_defines: Vec<(OsString, OsString)>,
+    initial_cache: String,
+    skip_install_all_dependency: bool,
+    list_static_libs: bool,
+    list_shared_libs: bool,
+    surface_cmake_warnings: bool,
+    configure_args: Vec<OsString>,
+    wasi_sdk: Option<PathBuf>,
+    defines_via_cache_file: Option<bool>,
+    pkgconfig_deps: Vec<String>,
+    undefines: Vec<OsString>,
+}
+
+/// A cmake build type, as passed through `CMAKE_BUILD_TYPE` and `--config`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BuildType {
+    /// The `Debug` build type.
+    Debug,
+    /// The `Release` build type.
+    Release,
+    /// The `RelWithDebInfo` build type.
+    RelWithDebInfo,
+    /// The `MinSizeRel` build type.
+    MinSizeRel,
+    /// A build type defined by the project itself.
+    Custom(String),
+}
+
+impl BuildType {
+    /// Returns the name cmake knows this build type by.
+    pub fn as_str(&self) -> &str {
+        match *self {
+            BuildType::Debug => "Debug",
+            BuildType::Release => "Release",
+            BuildType::RelWithDebInfo => "RelWithDebInfo",
+            BuildType::MinSizeRel => "MinSizeRel",
+            BuildType::Custom(ref s) => s,
+        }
+    }
+}
+
+impl<'a> From<&'a str> for BuildType {
+    fn from(name: &'a str) -> BuildType {
+        match name {
+            "Debug" => BuildType::Debug,
+            "Release" => BuildType::Release,
+            "RelWithDebInfo" => BuildType::RelWithDebInfo,
+            "MinSizeRel" => BuildType::MinSizeRel,
+            _ => BuildType::Custom(name.to_string()),
+        }
+    }
+}
+
+impl From<String> for BuildType {
+    fn from(name: String) -> BuildType {
+        BuildType::from(&*name)
+    }
+}
+
+/// How much of the cmake invocations' output is shown.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verbosity {
+    /// Capture all output. On success only a one-line summary is printed; on
+    /// failure the captured output is replayed.
+    Quiet,
+    /// Stream the output of cmake and the native build tool as it runs.
+    Normal,
+    /// Like `Normal`, but also asks cmake and the build tool for verbose
+    /// output (see `very_verbose`).
+    Verbose,
+}
+
+/// A set of kinds of compiler flags, used to select which of the flags
+/// determined by the `cc` crate are forwarded to cmake. Kinds can be combined
+/// with `|`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CcFlagKinds(u8);
+
+impl CcFlagKinds {
+    /// No flags at all.
+    pub const NONE: CcFlagKinds = CcFlagKinds(0);
+    /// Flags selecting the target, architecture and sysroot, such as
+    /// `--target=...`, `-m64`, `-arch` or `--sysroot`.
+    pub const TARGET: CcFlagKinds = CcFlagKinds(1);
+    /// Warning flags, such as `-Wall` or `/W4`.
+    pub const WARNINGS: CcFlagKinds = CcFlagKinds(2);
+    /// Every other flag, such as `-fPIC`, `-ffunction-sections` or `/MD`.
+    pub const OTHER: CcFlagKinds = CcFlagKinds(4);
+    /// All of the kinds above.
+    pub const ALL: CcFlagKinds = CcFlagKinds(7);
+
+    /// Returns whether every kind in `other` is also in `self`.
+    pub fn contains(self, other: CcFlagKinds) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for CcFlagKinds {
+    type Output = CcFlagKinds;
+
+    fn bitor(self, other: CcFlagKinds) -> CcFlagKinds {
+        CcFlagKinds(self.0 | other.0)
+    }
+}
+
+/// The contents of a pkg-config `.pc` file installed by a project, see
+/// `Config::parse_pc_file`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PkgConfig {
+    /// The libraries listed with `-l` in `Libs`.
+    pub libs: Vec<String>,
+    /// The directories listed with `-L` in `Libs`.
+    pub link_paths: Vec<PathBuf>,
+    /// The directories listed with `-I` in `Cflags`.
+    pub include_paths: Vec<PathBuf>,
+    /// The macros listed with `-D` in `Cflags`, with their optional values.
+    pub defines: Vec<(String, Option<String>)>,
+    /// The names of the packages listed in `Requires`, without any version
+    /// constraints.
+    pub requires: Vec<String>,
+}
+
+impl PkgConfig {
+    /// Prints the `cargo:rustc-link-search` and `cargo:rustc-link-lib`
+    /// directives for the libraries described by this file.
+    pub fn print_cargo_metadata(&self) {
+        for path in &self.link_paths {
+            println!("cargo:rustc-link-search=native={}", path.display());
+        }
+        for lib in &self.libs {
+            println!("cargo:rustc-link-lib={}", lib);
+        }
+    }
+}
+
+/// The family of native build tools a cmake generator produces files for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GeneratorKind {
+    /// Makefile generators run by a make flavor, e.g. `Unix Makefiles`,
+    /// `MinGW Makefiles` or `MSYS Makefiles`.
+    Make,
+    /// The NMake generators, run by `nmake` or `jom`.
+    NMake,
+    /// The Ninja generators.
+    Ninja,
+    /// The Visual Studio generators, run by `msbuild`.
+    VisualStudio,
+    /// The Xcode generator, run by `xcodebuild`.
+    Xcode,
+}
+
+impl GeneratorKind {
+    fn of(generator: &str) -> Option<GeneratorKind> {
+        if generator.contains("Ninja") {
+            Some(GeneratorKind::Ninja)
+        } else if generator.starts_with("Visual Studio") {
+            Some(GeneratorKind::VisualStudio)
+        } else if generator.contains("NMake") {
+            Some(GeneratorKind::NMake)
+        } else if generator.contains("Makefiles") {
+            Some(GeneratorKind::Make)
+        } else if generator == "Xcode" {
+            Some(GeneratorKind::Xcode)
+        } else {
+            None
+        }
+    }
+}
+
+/// What a build produced, as returned by `Config::build_artifacts`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BuildArtifacts {
+    /// The directory the project was installed into, as returned by `build`.
+    pub install_prefix: PathBuf,
+    /// The cmake build directory.
+    pub build_dir: PathBuf,
+    /// The files installed by the `install` target, as listed in cmake's
+    /// `install_manifest.txt`.
+    pub installed_files: Vec<PathBuf>,
+    /// The `include` directory of the install prefix, which only exists if
+    /// the project installed headers.
+    pub include_dir: PathBuf,
+    /// The `lib` and `lib64` directories of the install prefix that exist.
+    pub lib_dirs: Vec<PathBuf>,
+    /// The libraries installed into `lib_dirs`, named like `static=foo` or
+    /// `dylib=foo`, see `Config::build_libs`.
+    pub libs: Vec<String>,
+    /// The `cargo:` lines this crate produced during the build, whether or
+    /// not they were printed (see `Config::cargo_metadata`).
+    pub metadata: Vec<String>,
+}
+
+impl BuildArtifacts {
+    /// Prints the `cargo:rustc-link-search` and `cargo:rustc-link-lib`
+    /// directives for the installed libraries.
+    pub fn print_cargo_metadata(&self) {
+        for dir in &self.lib_dirs {
+            println!("cargo:rustc-link-search=native={}", dir.display());
+        }
+        for lib in &self.libs {
+            println!("cargo:rustc-link-lib={}", lib);
+        }
+    }
+}
+
+/// The variables of a cmake cache, as returned by `read_cache`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheVars {
+    vars: HashMap<String, (String, String)>,
+}
+
+impl CacheVars {
+    /// Returns the value of the cache variable `name`, if it's set.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.vars.get(name).map(|(_, value)| &value[..])
+    }
+
+    /// Returns the type of the cache variable `name`, e.g. `BOOL`, `PATH` or
+    /// `INTERNAL`, if it's set.
+    pub fn get_type(&self, name: &str) -> Option<&str> {
+        self.vars.get(name).map(|(ty, _)| &ty[..])
+    }
+
+    /// Returns the names of all the variables in the cache.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.vars.keys().map(|name| &name[..])
+    }
+}
+
+/// A step of a cmake build.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Step {
+    /// The build system generation step, `cmake <path>`.
+    Configure,
+    /// The build step, `cmake --build`.
+    Build,
+    /// The install step, `cmake --install`.
+    Install,
+}
+
+/// An error returned by `Config::try_build` and `Config::try_configure`.
+#[derive(Debug)]
+pub enum Error {
+    /// The program couldn't be found, usually because cmake isn't installed.
+    CommandNotFound {
+        /// The name of the program.
+        program: String,
+    },
+    /// A cmake invocation exited unsuccessfully.
+    CommandFailed {
+        /// The step that was being run.
+        step: Step,
+        /// The exit status of the invocation.
+        status: ExitStatus,
+        /// The last lines written to standard error, if the output was
+        /// captured, see `Config::capture_output`.
+        stderr: String,
+    },
+    /// A command couldn't be run, or a directory needed by the build
+    /// couldn't be created.
+    Io(io::Error),
+    /// An environment variable Cargo sets for build scripts wasn't set.
+    MissingEnv {
+        /// The name of the variable.
+        var: String,
+    },
+    /// A step exceeded the time limit set with `Config::configure_timeout`
+    /// or `Config::build_timeout`, and was killed.
+    Timeout {
+        /// The step that was being run.
+        phase: Step,
+    },
+    /// The project can't be built as configured, for example because the
+    /// cmake found is older than required or the target isn't supported.
+    Config(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::CommandNotFound { ref program } => write!(
+                f,
+                "failed to execute command: program not found\nis `{}` not installed?",
+                program
+            ),
+            Error::CommandFailed {
+                status, ref stderr, ..
+            } => {
+                write!(f, "command did not execute successfully, got: {}", status)?;
+                if !stderr.is_empty() {
+                    write!(f, "\n\n{}", stderr)?;
+                }
+                Ok(())
+            }
+            Error::Io(ref e) => write!(f, "{}", e),
+            Error::MissingEnv { ref var } => {
+                write!(f, "environment variable `{}` not defined", var)
+            }
+            Error::Timeout { phase } => {
+                let phase = match phase {
+                    Step::Configure => "configure",
+                    Step::Build => "build",
+                    Step::Install => "install",
+                };
+                write!(f, "the cmake {} step timed out", phase)
+            }
+            Error::Config(ref msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 /// Builds the native library rooted at `path` with the default cmake options.
@@ -103,6 +497,152 @@ pub fn build<P: AsRef<Path>>(path: P) -> PathBuf {
     Config::new(path.as_ref()).build()
 }
 
+/// Builds the native library rooted at `path` like `build`, additionally
+/// passing each of `defines` as a `-D` flag to cmake.
+///
+/// # Examples
+///
+/// ```no_run
+/// use cmake;
+///
+/// let dst = cmake::build_with("libfoo", vec![("FOO_SHARED", "OFF")]);
+///
+/// println!("cargo:rustc-link-search=native={}", dst.display());
+/// println!("cargo:rustc-link-lib=static=foo");
+/// ```
+///
+pub fn build_with<P, I, K, V>(path: P, defines: I) -> PathBuf
+where
+    P: AsRef<Path>,
+    I: IntoIterator<Item = (K, V)>,
+    K: AsRef<OsStr>,
+    V: AsRef<OsStr>,
+{
+    let mut config = Config::new(path.as_ref());
+    for (k, v) in defines {
+        config.define(k, v);
+    }
+    config.build()
+}
+
+/// Returns the `(major, minor, patch)` version of the cmake executable that
+/// builds use, i.e. `$CMAKE` or else `cmake`, or `None` if it can't be run.
+///
+/// The version is determined once and cached.
+pub fn cmake_version() -> Option<(u32, u32, u32)> {
+    executable_version(&env::var_os("CMAKE").unwrap_or_else(|| OsString::from("cmake")))
+}
+
+/// Merges the compilation databases found at `paths` into a single
+/// `compile_commands.json` written to `out`.
+///
+/// Each path may either be a `compile_commands.json` file or a cmake build
+/// directory containing one, such as `dst.join("build")` for the `dst`
+/// returned by `Config::build` with `export_compile_commands` enabled, or
+/// `dst.join(name)` if the directory was renamed with
+/// `Config::build_dir_name`.
+///
+/// ```no_run
+/// use std::path::Path;
+///
+/// let foo = cmake::Config::new("libfoo").export_compile_commands(true).build();
+/// let bar = cmake::Config::new("libbar").export_compile_commands(true).build();
+/// cmake::merge_compile_commands(
+///     &[&foo.join("build"), &bar.join("build")],
+///     Path::new("compile_commands.json"),
+/// )
+/// .unwrap();
+/// ```
+pub fn merge_compile_commands(paths: &[&Path], out: &Path) -> io::Result<()> {
+    let mut entries = Vec::new();
+    for path in paths {
+        let file = if path.is_dir() {
+            path.join("compile_commands.json")
+        } else {
+            path.to_path_buf()
+        };
+        let contents = fs::read_to_string(&file)?;
+        let contents = contents.trim();
+        if !contents.starts_with('[') || !contents.ends_with(']') {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{} is not a compilation database", file.display()),
+            ));
+        }
+        let inner = contents[1..contents.len() - 1].trim();
+        if !inner.is_empty() {
+            entries.push(inner.to_string());
+        }
+    }
+
+    let mut merged = String::from("[\n");
+    merged.push_str(&entries.join(",\n"));
+    merged.push_str("\n]\n");
+    fs::write(out, merged)
+}
+
+/// Reads the `CMakeCache.txt` of the configured build directory `build_dir`,
+/// e.g. `dst.join("build")` for the `dst` returned by `Config::build`. See
+/// also `Config::cache_file_path`.
+///
+/// This lets a build script look at what cmake detected while configuring,
+/// such as whether an optional dependency was found:
+///
+/// ```no_run
+/// let dst = cmake::build("libfoo");
+/// let cache = cmake::read_cache(&dst.join("build")).unwrap();
+/// if cache.get("FOO_HAVE_ZLIB") == Some("ON") {
+///     println!("cargo:rustc-cfg=foo_zlib");
+/// }
+/// ```
+pub fn read_cache(build_dir: &Path) -> io::Result<CacheVars> {
+    let contents = fs::read_to_string(build_dir.join("CMakeCache.txt"))?;
+    let mut vars = HashMap::new();
+    for line in contents.lines() {
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with("//") || line.starts_with('#') {
+            continue;
+        }
+        // Entries are `NAME:TYPE=VALUE`, with the name quoted if it contains
+        // a `:` or `=`.
+        let (name, rest) = if let Some(quoted) = line.strip_prefix('"') {
+            match quoted.split_once('"') {
+                Some(parts) => parts,
+                None => continue,
+            }
+        } else {
+            match line.find([':', '=']) {
+                Some(i) => line.split_at(i),
+                None => continue,
+            }
+        };
+        let (ty, value) = match rest.split_once('=') {
+            Some((ty, value)) => (ty.strip_prefix(':').unwrap_or(ty), value),
+            None => continue,
+        };
+        vars.insert(name.to_string(), (ty.to_string(), value.to_string()));
+    }
+    Ok(CacheVars { vars })
+}
+
+/// Removes the cmake build directory `out_dir/build`, so that the next build
+/// configures the project from scratch.
+///
+/// `out_dir` is the directory returned by `Config::build`, or the build
+/// script's `OUT_DIR` by default. Nothing happens if the build directory
+/// doesn't exist. Use `Config::clean` for a build directory renamed with
+/// `Config::build_dir_name`.
+pub fn clean(out_dir: &Path) -> io::Result<()> {
+    remove_build_dir(&out_dir.join("build"))
+}
+
+fn remove_build_dir(dir: &Path) -> io::Result<()> {
+    match fs::remove_dir_all(dir) {
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
 impl Config {
     /// Creates a new blank set of configuration to build the project specified
     /// at the path `path`.
@@ -113,7 +653,10 @@ impl Config {
             cflags: OsString::new(),
             cxxflags: OsString::new(),
             asmflags: OsString::new(),
+            config_cflags: Vec::new(),
+            config_cxxflags: Vec::new(),
             defines: Vec::new(),
+            lazy_defines: Vec::new(),
             deps: Vec::new(),
             profile: None,
             out_dir: None,
@@ -123,22 +666,103 @@ impl Config {
             cmake_target: None,
             env: Vec::new(),
             static_crt: None,
+            osx_sysroot: None,
             uses_cxx11: false,
             always_configure: true,
             no_build_target: false,
             verbose_cmake: false,
             verbose_make: false,
+            cargo_metadata: true,
+            graphviz: None,
+            env_cache: Mutex::new(HashMap::new()),
+            required_cmake_version: None,
+            shared_libs_dir: None,
+            generated_toolchain_file: false,
+            rerun_if_changed: false,
+            rerun_excludes: Vec::new(),
+            preset: None,
+            presets_file: None,
+            whole_archive_libs: Vec::new(),
+            try_compile_static: false,
+            link_args: Vec::new(),
+            verbosity: Verbosity::Normal,
+            prefix_path_files: Vec::new(),
+            export_compile_commands: false,
+            no_warning_as_error: false,
+            install_default_permissions: None,
+            find_debug: false,
+            cc_flag_kinds: CcFlagKinds::ALL,
+            emit_timings: false,
+            external_project_prefix: None,
+            target_triple_var: None,
+            build_target_sequence: Vec::new(),
+            probe_dir: None,
+            msvc_prefer_makefiles: false,
+            warnings_as_errors: false,
+            universal_archs: Vec::new(),
+            hidden_visibility: false,
+            configure_timeout: None,
+            build_timeout: None,
+            relocatable_install: false,
+            native_tool_args: Vec::new(),
+            metadata: Mutex::new(Vec::new()),
+            linker: None,
+            rust_linker: true,
+            pch: None,
+            cargo_directives_file: None,
+            copy_source: false,
+            parallel: None,
+            cross_defaults: false,
+            env_clear: false,
+            sysroot_search_paths: true,
+            ldflags: OsString::new(),
+            verbose_build: false,
+            lto: false,
+            bootstrap: None,
+            prune_stale_installs: false,
+            pic: None,
+            build_all_targets: false,
+            message_context: false,
+            header_only: false,
+            capture_output: false,
+            root_metadata_path: None,
+            existing_build_dir: None,
+            osx_deployment_target: None,
+            android_ndk: None,
+            android_api_level: None,
+            build_report: None,
+            build_dir_name: PathBuf::from("build"),
+            musl_static: None,
+            musl_sysroot: None,
+            no_default_compiler: false,
+            max_load: None,
+            compiler_launcher: None,
+            clean_first: false,
+            forced_defines: Vec::new(),
+            initial_cache: String::new(),
+            skip_install_all_dependency: false,
+            list_static_libs: true,
+            list_shared_libs: true,
+            surface_cmake_warnings: false,
+            configure_args: Vec::new(),
+            wasi_sdk: None,
+            defines_via_cache_file: None,
+            pkgconfig_deps: Vec::new(),
+            undefines: Vec::new(),
         }
     }
 
     /// Sets the build-tool generator (`-G`) for this compilation.
+    ///
+    /// If no generator is set, the `CMAKE_GENERATOR` environment variable is
+    /// honored before falling back to this crate's platform defaults.
     pub fn generator<T: AsRef<OsStr>>(&mut self, generator: T) -> &mut Config {
         self.generator = Some(generator.as_ref().to_owned());
         self
     }
 
     /// Adds a custom flag to pass down to the C compiler, supplementing those
-    /// that this library already passes.
+    /// that this library already passes and those in `CFLAGS`.
     pub fn cflag<P: AsRef<OsStr>>(&mut self, flag: P) -> &mut Config {
         self.cflags.push(" ");
         self.cflags.push(flag.as_ref());
@@ -146,7 +770,7 @@ impl Config {
     }
 
     /// Adds a custom flag to pass down to the C++ compiler, supplementing those
-    /// that this library already passes.
+    /// that this library already passes and those in `CXXFLAGS`.
     pub fn cxxflag<P: AsRef<OsStr>>(&mut self, flag: P) -> &mut Config {
         self.cxxflags.push(" ");
         self.cxxflags.push(flag.as_ref());
@@ -161,17 +785,258 @@ impl Config {
         self
     }
 
+    /// Adds a custom flag to pass down to the linker when linking executables,
+    /// shared libraries and modules.
+    ///
+    /// The flags are passed as `CMAKE_EXE_LINKER_FLAGS`,
+    /// `CMAKE_SHARED_LINKER_FLAGS` and `CMAKE_MODULE_LINKER_FLAGS`.
+    pub fn ldflag<P: AsRef<OsStr>>(&mut self, flag: P) -> &mut Config {
+        self.ldflags.push(" ");
+        self.ldflags.push(flag.as_ref());
+        self
+    }
+
+    /// Adds a custom flag to pass down to the C compiler only when building
+    /// the given configuration (e.g. `Debug` or `Release`).
+    ///
+    /// These flags are appended to the defaults cmake uses for that
+    /// configuration through `CMAKE_C_FLAGS_<CONFIG>_INIT`, and so only take
+    /// effect when the cmake cache is first created.
+    ///
+    /// Panics if `config` isn't a valid configuration name.
+    pub fn cflag_for_config<P: AsRef<OsStr>>(&mut self, config: &str, flag: P) -> &mut Config {
+        push_config_flag(&mut self.config_cflags, config, flag.as_ref());
+        self
+    }
+
+    /// Adds a custom flag to pass down to the C++ compiler only when building
+    /// the given configuration (e.g. `Debug` or `Release`).
+    ///
+    /// See `cflag_for_config` for how these flags are passed to cmake.
+    pub fn cxxflag_for_config<P: AsRef<OsStr>>(&mut self, config: &str, flag: P) -> &mut Config {
+        push_config_flag(&mut self.config_cxxflags, config, flag.as_ref());
+        self
+    }
+
     /// Adds a new `-D` flag to pass to cmake during the generation step.
+    ///
+    /// If the same variable is defined more than once, only the last value is
+    /// passed to cmake and a warning is emitted.
+    ///
+    /// The whole `-DKEY=value` is passed to cmake as a single argument, so
+    /// values may safely contain spaces, and since cmake splits the argument
+    /// at the first `=`, also `=`. Keys can't contain `=` and must not be
+    /// empty; this panics otherwise.
+    ///
+    /// Variables selecting the toolchain or platform (`CMAKE_TOOLCHAIN_FILE`,
+    /// `CMAKE_SYSTEM_*`, `CMAKE_SYSROOT`, `CMAKE_OSX_*`, the compilers and
+    /// `BUILD_SHARED_LIBS`) are passed before all other defines. Otherwise
+    /// defines are passed in the order they were added.
     pub fn define<K, V>(&mut self, k: K, v: V) -> &mut Config
     where
         K: AsRef<OsStr>,
         V: AsRef<OsStr>,
     {
+        check_define_key(k.as_ref());
         self.defines
             .push((k.as_ref().to_owned(), v.as_ref().to_owned()));
         self
     }
 
+    /// Removes the variable `k` from the cache when configuring, passed as
+    /// `-U<k>`, along with any earlier `define` of it.
+    ///
+    /// This clears values left over from an earlier configuration of the
+    /// build directory. cmake treats `k` as a globbing expression, so e.g.
+    /// `FOO_*` removes all the variables starting with `FOO_`.
+    pub fn undefine<K: AsRef<OsStr>>(&mut self, k: K) -> &mut Config {
+        let name = define_name(k.as_ref());
+        self.defines.retain(|(a, _)| define_name(a) != name);
+        self.undefines.push(k.as_ref().to_owned());
+        self
+    }
+
+    /// Adds a new boolean `-D` flag, passed as `ON` or `OFF`.
+    pub fn define_bool<K: AsRef<OsStr>>(&mut self, k: K, value: bool) -> &mut Config {
+        self.define(k, if value { "ON" } else { "OFF" })
+    }
+
+    /// Adds a new `-D` flag with an explicit cache entry type, passed as
+    /// `-D<k>:<ty>=<v>`, e.g. `BOOL`, `STRING`, `PATH` or `FILEPATH`.
+    ///
+    /// The variable is otherwise treated like one passed to `define`.
+    pub fn define_typed<K, V>(&mut self, k: K, ty: &str, v: V) -> &mut Config
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        let mut key = k.as_ref().to_owned();
+        key.push(":");
+        key.push(ty);
+        self.define(key, v)
+    }
+
+    /// Forces the cache variable `k` to `v` through an initial cache script,
+    /// passed as `-C`, which runs `set(<k> <v> CACHE STRING "" FORCE)`.
+    ///
+    /// Unlike `define`, the value replaces whatever the cache holds before the
+    /// project is processed, even an entry of a different type. A project
+    /// that itself sets the variable with `FORCE` still has the last word,
+    /// just like with `define`. The key may carry a type like `define_typed`
+    /// takes, e.g. `FOO:BOOL`.
+    pub fn define_force<K, V>(&mut self, k: K, v: V) -> &mut Config
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        self.forced_defines
+            .push((k.as_ref().to_owned(), v.as_ref().to_owned()));
+        self
+    }
+
+    /// Runs the cmake script `path` to pre-populate the cache, like passing
+    /// it with `-C`.
+    ///
+    /// Initial cache scripts run before the project's `CMakeLists.txt`, and
+    /// can set cache entries and policies with `set(... CACHE ...)`.
+    pub fn initial_cache<P: AsRef<Path>>(&mut self, path: P) -> &mut Config {
+        let path = env::current_dir().unwrap().join(path);
+        self.initial_cache.push_str(&format!(
+            "include(\"{}\")\n",
+            cmake_quote(&path.to_string_lossy())
+        ));
+        self
+    }
+
+    /// Like `initial_cache`, but runs the cmake code `script` given inline.
+    ///
+    /// The code is written to `cmake-rs-initial-cache.cmake` in the build
+    /// directory, which is only rewritten when the code changes.
+    pub fn initial_cache_script(&mut self, script: &str) -> &mut Config {
+        self.initial_cache.push_str(script);
+        if !script.ends_with('\n') {
+            self.initial_cache.push('\n');
+        }
+        self
+    }
+
+    /// Adds a new `-D` flag whose value is a cmake list of `values`.
+    ///
+    /// The values are joined with `;`, escaping any `;` within a value so it
+    /// stays a single list element. Like `define`, elements may contain
+    /// spaces.
+    pub fn define_list<K, I, V>(&mut self, k: K, values: I) -> &mut Config
+    where
+        K: AsRef<OsStr>,
+        I: IntoIterator<Item = V>,
+        V: AsRef<OsStr>,
+    {
+        let mut list = OsString::new();
+        for (i, value) in values.into_iter().enumerate() {
+            if i > 0 {
+                list.push(";");
+            }
+            match value.as_ref().to_str() {
+                Some(s) => list.push(s.replace(';', "\\;")),
+                None => list.push(value.as_ref()),
+            }
+        }
+        self.define(k, list)
+    }
+
+    /// Adds a `-D` flag for each key of the table `table` in the TOML file at
+    /// `path`.
+    ///
+    /// `table` is the name of the table as written in its header, e.g.
+    /// `native.options` for `[native.options]`. Values are converted as
+    /// follows:
+    ///
+    /// * strings are passed as is
+    /// * booleans become `ON` or `OFF`
+    /// * integers and floats are passed as written
+    /// * arrays of the above become cmake lists, joined with `;`
+    ///
+    /// Nested arrays, inline tables and multi-line strings aren't supported.
+    /// Malformed input, or a file without the table, makes this panic with
+    /// the offending line.
+    pub fn defines_from_toml<P: AsRef<Path>>(&mut self, path: P, table: &str) -> &mut Config {
+        let path = env::current_dir().unwrap().join(path);
+        self.print(&format!("cargo:rerun-if-changed={}", path.display()));
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) => fail(&format!("failed to read {}: {}", path.display(), e)),
+        };
+        match parse_toml_defines(&contents, table) {
+            Ok(defines) => {
+                for (k, v) in defines {
+                    self.define(k, v);
+                }
+            }
+            Err((0, msg)) => fail(&format!("{}: {}", path.display(), msg)),
+            Err((line, msg)) => fail(&format!("{}:{}: {}", path.display(), line, msg)),
+        }
+        self
+    }
+
+    /// Adds a new `-D` flag whose value is computed by `f` when `build` runs,
+    /// e.g. because it's expensive to compute.
+    ///
+    /// Deferred values are computed in the order they were registered at the
+    /// start of `build`, and are passed after all the values given to `define`.
+    pub fn define_with<K, F, V>(&mut self, k: K, f: F) -> &mut Config
+    where
+        K: AsRef<OsStr>,
+        F: FnOnce() -> V + Send + 'static,
+        V: AsRef<OsStr>,
+    {
+        check_define_key(k.as_ref());
+        self.lazy_defines.push((
+            k.as_ref().to_owned(),
+            Box::new(move || f().as_ref().to_owned()),
+        ));
+        self
+    }
+
+    /// Adds a new `-D` flag whose value is the trimmed standard output of
+    /// running `program` with `args`, e.g. `git rev-parse HEAD`.
+    ///
+    /// The command is run when `build` runs, see `define_with`. The build fails
+    /// if the command can't be run or exits unsuccessfully.
+    pub fn define_from_command<K: AsRef<OsStr>>(
+        &mut self,
+        k: K,
+        program: &str,
+        args: &[&str],
+    ) -> &mut Config {
+        let program = program.to_string();
+        let args = args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        self.define_with(k, move || {
+            let mut command = format!("`{}", program);
+            for arg in &args {
+                command.push(' ');
+                command.push_str(arg);
+            }
+            command.push('`');
+            let output = match Command::new(&program).args(&args).output() {
+                Ok(output) => output,
+                Err(ref e) if e.kind() == ErrorKind::NotFound => fail(&format!(
+                    "failed to run {}: `{}` not found",
+                    command, program
+                )),
+                Err(e) => fail(&format!("failed to run {}: {}", command, e)),
+            };
+            if !output.status.success() {
+                fail(&format!(
+                    "{} failed with {}:\n{}",
+                    command,
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim_end()
+                ));
+            }
+            String::from_utf8_lossy(&output.stdout).trim().to_string()
+        })
+    }
+
     /// Registers a dependency for this compilation on the native library built
     /// by Cargo previously.
     ///
@@ -182,6 +1047,29 @@ impl Config {
         self
     }
 
+    /// Like `register_dep`, but also adds the `lib/pkgconfig` and
+    /// `lib64/pkgconfig` directories of the dependency to the
+    /// `PKG_CONFIG_PATH` environment variable for the build system generation
+    /// step, so that the project can find it with `pkg-config`.
+    pub fn register_dep_pkgconfig(&mut self, dep: &str) -> &mut Config {
+        self.pkgconfig_deps.push(dep.to_string());
+        self.register_dep(dep)
+    }
+
+    /// Adds the prefix directories listed in the file at `path` to
+    /// `CMAKE_PREFIX_PATH` for the build system generation step.
+    ///
+    /// The file lists one directory per line; blank lines and lines starting
+    /// with `#` are ignored. Relative directories are resolved against the
+    /// directory containing the file. The entries are searched after those of
+    /// dependencies added with `register_dep`, and before any
+    /// `CMAKE_PREFIX_PATH` from the environment.
+    pub fn prefix_path_from_file<P: AsRef<Path>>(&mut self, path: P) -> &mut Config {
+        self.prefix_path_files
+            .push(env::current_dir().unwrap().join(path));
+        self
+    }
+
     /// Sets the target triple for this compilation.
     ///
     /// This is automatically scraped from `$TARGET` which is set for Cargo
@@ -191,7 +1079,9 @@ impl Config {
         self
     }
 
-    /// Disables the cmake target option for this compilation.
+    /// Disables the cmake target option for this compilation, so that the
+    /// default target (`all`, or `ALL_BUILD` for Visual Studio) is built
+    /// instead of `install`.
     ///
     /// Note that this isn't related to the target triple passed to the compiler!
     pub fn no_build_target(&mut self, no_build_target: bool) -> &mut Config {
@@ -208,6 +1098,20 @@ impl Config {
         self
     }
 
+    /// Returns the target triple this project is built for, as set with
+    /// `target` or else taken from `$TARGET`.
+    pub fn get_target(&self) -> String {
+        self.get_target_triple()
+    }
+
+    /// Returns the host triple of this compilation, as set with `host` or
+    /// else taken from `$HOST`.
+    ///
+    /// The project is cross compiled when this differs from `get_target`.
+    pub fn get_host(&self) -> String {
+        self.get_host_triple()
+    }
+
     /// Sets the output directory for this compilation.
     ///
     /// This is automatically scraped from `$OUT_DIR` which is set for Cargo
@@ -227,25 +1131,116 @@ impl Config {
     ///   * `debug=false` then `CMAKE_BUILD_TYPE=Release`
     ///   * otherwise `CMAKE_BUILD_TYPE=RelWithDebInfo`
     /// * if `opt-level={s,z}` then `CMAKE_BUILD_TYPE=MinSizeRel`
+    ///
+    /// A warning is printed if `profile` isn't one of cmake's standard build
+    /// types; use `build_type` with `BuildType::Custom` for project-defined
+    /// ones.
+    ///
+    /// The profile is honored for MSVC targets as well. Note that Rust always
+    /// links against the release CRT, whereas cmake's default flags for the
+    /// `Debug` build type select the debug CRT (`/MDd` or `/MTd`). A `Debug`
+    /// native build therefore needs the release CRT selected explicitly to
+    /// link with a Rust binary, by defining `CMAKE_MSVC_RUNTIME_LIBRARY` as
+    /// `MultiThreadedDLL`, or `MultiThreaded` for a static CRT. This requires
+    /// cmake 3.15 or later, and projects requiring an older version also need
+    /// `CMAKE_POLICY_DEFAULT_CMP0091` defined as `NEW`.
     pub fn profile(&mut self, profile: &str) -> &mut Config {
+        let known = ["Debug", "Release", "RelWithDebInfo", "MinSizeRel"];
+        if !known.contains(&profile) {
+            match known.iter().find(|k| k.eq_ignore_ascii_case(profile)) {
+                Some(k) => self.print(&format!(
+                    "cargo:warning=unknown cmake build type `{}`, did you mean `{}`?",
+                    profile, k
+                )),
+                None => self.print(&format!(
+                    "cargo:warning=unknown cmake build type `{}`",
+                    profile
+                )),
+            }
+        }
         self.profile = Some(profile.to_string());
         self
     }
 
+    /// Sets the `CMAKE_BUILD_TYPE` variable, see `profile`.
+    ///
+    /// This accepts a `BuildType` or its name, e.g. `"MinSizeRel"`, and
+    /// replaces the build type derived from the Cargo profile. The name is
+    /// used verbatim for both `CMAKE_BUILD_TYPE` and `--config`.
+    pub fn build_type<T: Into<BuildType>>(&mut self, build_type: T) -> &mut Config {
+        self.profile = Some(build_type.into().as_str().to_string());
+        self
+    }
+
     /// Configures whether the /MT flag or the /MD flag will be passed to msvc build tools.
     ///
+    /// With cmake 3.15 or later this also sets `CMAKE_MSVC_RUNTIME_LIBRARY`,
+    /// selecting the debug variant of the CRT for `Debug` builds, which
+    /// doesn't match the release CRT Rust links against; define
+    /// `CMAKE_MSVC_RUNTIME_LIBRARY` yourself to choose the variant, see
+    /// `profile`.
+    ///
     /// This option defaults to `false`, and affect only msvc targets.
     pub fn static_crt(&mut self, static_crt: bool) -> &mut Config {
         self.static_crt = Some(static_crt);
         self
     }
 
+    /// Sets the minimum OS version of Apple targets, passed to cmake as
+    /// `CMAKE_OSX_DEPLOYMENT_TARGET`.
+    ///
+    /// By default this is taken from `MACOSX_DEPLOYMENT_TARGET` or
+    /// `IPHONEOS_DEPLOYMENT_TARGET`, like rustc does.
+    pub fn osx_deployment_target<T: AsRef<str>>(&mut self, version: T) -> &mut Config {
+        self.osx_deployment_target = Some(version.as_ref().to_string());
+        self
+    }
+
+    /// Sets the Apple SDK used to build for Apple targets, e.g. `macosx10.15`
+    /// or `iphonesimulator`.
+    ///
+    /// The SDK is resolved to a path with `xcrun --sdk <sdk> --show-sdk-path`
+    /// and passed as `CMAKE_OSX_SYSROOT`. By default the SDK is inferred from
+    /// the target (`macosx`, `iphoneos` or `iphonesimulator`). If `xcrun` isn't
+    /// available the SDK name is passed through to cmake as-is.
+    pub fn osx_sysroot(&mut self, sdk: &str) -> &mut Config {
+        self.osx_sysroot = Some(sdk.to_string());
+        self
+    }
+
+    /// Adds an argument to the initial `cmake` configure step, such as
+    /// `-Wno-dev` or `--log-level=VERBOSE`, passed after the defines.
+    ///
+    /// A generator given here or in `CMAKE_ARGS` with `-G` takes the place of
+    /// the one set with `generator`.
+    pub fn configure_arg<A: AsRef<OsStr>>(&mut self, arg: A) -> &mut Config {
+        self.configure_args.push(arg.as_ref().to_owned());
+        self
+    }
+
     /// Add an argument to the final `cmake` build step
     pub fn build_arg<A: AsRef<OsStr>>(&mut self, arg: A) -> &mut Config {
         self.build_args.push(arg.as_ref().to_owned());
         self
     }
 
+    /// Adds an argument to the final `cmake` build step that is only passed
+    /// when the generator in use is of the given kind.
+    ///
+    /// This allows one `Config` to carry arguments for several native build
+    /// tools, e.g. `-k` for make and `/v:m` for msbuild. The generator is the
+    /// one set with `generator` or `CMAKE_GENERATOR`, or otherwise the one
+    /// this crate picks: Visual Studio for MSVC targets, and makefiles
+    /// elsewhere.
+    pub fn native_tool_arg_for(
+        &mut self,
+        generator_kind: GeneratorKind,
+        arg: OsString,
+    ) -> &mut Config {
+        self.native_tool_args.push((generator_kind, arg));
+        self
+    }
+
     /// Configure an environment variable for the `cmake` processes spawned by
     /// this crate in the `build` step.
     pub fn env<K, V>(&mut self, key: K, value: V) -> &mut Config
@@ -258,15 +1253,46 @@ impl Config {
         self
     }
 
+    /// Configures whether the `cmake` processes are started with a cleared
+    /// environment instead of inheriting the one of the build script.
+    ///
+    /// Only `PATH` is passed through, so that cmake and the build tools can
+    /// still be found, along with `SystemRoot`, `TEMP` and `TMP` on Windows,
+    /// which the MSVC tools need to run. The variables this crate sets itself
+    /// (such as `CMAKE_PREFIX_PATH`, `MAKEFLAGS` and the compiler's
+    /// environment) and those added with `env` are set as usual.
+    ///
+    /// This option defaults to `false`.
+    pub fn env_clear(&mut self, enable: bool) -> &mut Config {
+        self.env_clear = enable;
+        self
+    }
+
     /// Sets the build target for the final `cmake` build step, this will
     /// default to "install" if not specified.
+    ///
+    /// With cmake 3.15 or later the "install" target is built as a plain
+    /// `cmake --build` followed by `cmake --install`.
     pub fn build_target(&mut self, target: &str) -> &mut Config {
         self.cmake_target = Some(target.to_string());
         self
     }
 
-    /// Alters the default target triple on OSX to ensure that c++11 is
-    /// available. Does not change the target triple if it is explicitly
+    /// Sets a sequence of build targets that are built one after another,
+    /// each with its own `cmake --build` invocation, instead of the single
+    /// target set with `build_target`.
+    ///
+    /// Unlike a single invocation building several targets, this guarantees
+    /// their order, e.g. `clean` before `install`. The build stops at the
+    /// first target that fails. If `install` isn't part of the sequence,
+    /// `build` returns the build directory rather than the install prefix.
+    pub fn build_target_sequence(&mut self, targets: Vec<String>) -> &mut Config {
+        self.build_target_sequence = targets;
+        self
+    }
+
+    /// Alters the default target triple on OSX to ensure that c++11 is
+    /// available. Does not change the target triple if it is explicitly
     /// specified.
     ///
     /// This does not otherwise affect any CXX flags, i.e. it does not set
@@ -279,809 +1305,6182 @@ impl Config {
     /// Forces CMake to always run before building the custom target.
     ///
     /// In some cases, when you have a big project, you can disable
-    /// subsequents runs of cmake to make `cargo build` faster.
+    /// subsequents runs of cmake to make `cargo build` faster. The project is
+    /// still reconfigured if the configure command changed, e.g. because of a
+    /// new define or different compiler flags.
+    ///
+    /// This option defaults to `true`.
     pub fn always_configure(&mut self, always_configure: bool) -> &mut Config {
         self.always_configure = always_configure;
         self
     }
 
     /// Sets very verbose output.
+    ///
+    /// This enables cmake's debug output and `CMAKE_VERBOSE_MAKEFILE` when
+    /// configuring, and passes `--verbose` to `cmake --build` so that the
+    /// commands the build tool runs are shown. Before cmake 3.14 Makefile
+    /// builds get `VERBOSE=1` instead.
     pub fn very_verbose(&mut self, value: bool) -> &mut Config {
         self.verbose_cmake = value;
         self.verbose_make = value;
+        if value {
+            self.verbosity = Verbosity::Verbose;
+        } else if self.verbosity == Verbosity::Verbose {
+            self.verbosity = Verbosity::Normal;
+        }
         self
     }
 
-    // Simple heuristic to determine if we're cross-compiling using the Android
-    // NDK toolchain file.
-    fn uses_android_ndk(&self) -> bool {
-        // `ANDROID_ABI` is the only required flag:
-        // https://developer.android.com/ndk/guides/cmake#android_abi
-        self.defined("ANDROID_ABI")
-            && self.defines.iter().any(|(flag, value)| {
-                flag == "CMAKE_TOOLCHAIN_FILE"
-                    && Path::new(value).file_name() == Some("android.toolchain.cmake".as_ref())
-            })
+    /// Configures how much output the cmake invocations produce.
+    ///
+    /// With `Verbosity::Quiet` the output of cmake and the build tool is
+    /// captured rather than streamed, and a successful build prints a single
+    /// `cmake: built <project> (<target>) in <duration>` line instead. If any
+    /// step fails the captured output is replayed before panicking.
+    /// `Verbosity::Verbose` is equivalent to `very_verbose(true)`.
+    ///
+    /// This option defaults to `Verbosity::Normal`.
+    pub fn verbosity(&mut self, verbosity: Verbosity) -> &mut Config {
+        self.verbosity = verbosity;
+        self.verbose_cmake = verbosity == Verbosity::Verbose;
+        self.verbose_make = verbosity == Verbosity::Verbose;
+        self
     }
 
-    /// Run this configuration, compiling the library with all the configured
-    /// options.
+    /// Configures whether the build tool prints the commands it runs.
     ///
-    /// This will run both the build system generator command as well as the
-    /// command to build the library.
-    pub fn build(&mut self) -> PathBuf {
-        let target_triple = match self.target.clone() {
-            Some(t) => t,
-            None => {
-                let mut t = getenv_unwrap("TARGET");
-                if t.ends_with("-darwin") && self.uses_cxx11 {
-                    t = t + "11"
-                }
-                t
-            }
-        };
-        let target = get_target(&target_triple);
-        let host = self.host.clone().unwrap_or_else(|| getenv_unwrap("HOST"));
-        let msvc = target_triple.contains("msvc");
-        let ndk = self.uses_android_ndk();
-        let mut c_cfg = cc::Build::new();
-        c_cfg
-            .cargo_metadata(false)
-            .opt_level(0)
-            .debug(false)
-            .warnings(false)
-            .host(&host)
-            .no_default_flags(ndk);
-        if !ndk {
-            c_cfg.target(&target_triple);
-        }
-        let mut cxx_cfg = cc::Build::new();
-        cxx_cfg
-            .cargo_metadata(false)
-            .cpp(true)
-            .opt_level(0)
-            .debug(false)
-            .warnings(false)
-            .host(&host)
-            .no_default_flags(ndk);
-        if !ndk {
-            cxx_cfg.target(&target_triple);
-        }
-        if let Some(static_crt) = self.static_crt {
-            c_cfg.static_crt(static_crt);
-            cxx_cfg.static_crt(static_crt);
+    /// When enabled the configure command is echoed and `--verbose` is passed
+    /// to `cmake --build` (cmake 3.14+), overriding `Verbosity::Quiet`. When
+    /// disabled the output is left at the configured `verbosity`, which
+    /// defaults to `Verbosity::Normal`.
+    pub fn verbose(&mut self, enable: bool) -> &mut Config {
+        self.verbose_build = enable;
+        if enable && self.verbosity == Verbosity::Quiet {
+            self.verbosity = Verbosity::Normal;
         }
-        let c_compiler = c_cfg.get_compiler();
-        let cxx_compiler = cxx_cfg.get_compiler();
-        let asm_compiler = c_cfg.get_compiler();
+        self
+    }
 
-        let dst = self
-            .out_dir
-            .clone()
-            .unwrap_or_else(|| PathBuf::from(getenv_unwrap("OUT_DIR")));
-        let build = dst.join("build");
-        self.maybe_clear(&build);
-        let _ = fs::create_dir(&build);
+    /// Configures whether any `cargo:` metadata is printed on standard output.
+    ///
+    /// This is a master switch that, when disabled, suppresses every `cargo:`
+    /// line this crate would otherwise emit, including `cargo:root`.
+    ///
+    /// This option defaults to `true`.
+    pub fn cargo_metadata(&mut self, cargo_metadata: bool) -> &mut Config {
+        self.cargo_metadata = cargo_metadata;
+        self
+    }
 
-        // Add all our dependencies to our cmake paths
-        let mut cmake_prefix_path = Vec::new();
-        for dep in &self.deps {
-            let dep = dep.to_uppercase().replace('-', "_");
-            if let Some(root) = env::var_os(&format!("DEP_{}_ROOT", dep)) {
-                cmake_prefix_path.push(PathBuf::from(root));
-            }
-        }
-        let system_prefix = env::var_os("CMAKE_PREFIX_PATH").unwrap_or(OsString::new());
-        cmake_prefix_path.extend(env::split_paths(&system_prefix).map(|s| s.to_owned()));
-        let cmake_prefix_path = env::join_paths(&cmake_prefix_path).unwrap();
+    /// Writes a Graphviz description of the project's target dependency graph
+    /// to `path` when the build system is generated.
+    ///
+    /// This passes `--graphviz=<path>` to the generation step, which is useful
+    /// for debugging link ordering and target dependencies.
+    pub fn graphviz<P: AsRef<Path>>(&mut self, path: P) -> &mut Config {
+        self.graphviz = Some(env::current_dir().unwrap().join(path));
+        self
+    }
+
+    /// Requires at least version `major.minor` of cmake.
+    ///
+    /// The installed version is checked before anything else is run, failing
+    /// the build with a clear message if it's too old.
+    pub fn require_cmake_version(&mut self, major: u32, minor: u32) -> &mut Config {
+        self.required_cmake_version = Some((major, minor));
+        self
+    }
 
-        // Build up the first cmake command to build the build system.
-        let executable = env::var("CMAKE").unwrap_or("cmake".to_owned());
-        let mut cmd = Command::new(&executable);
+    /// Copies the shared libraries (`*.so`, `*.dylib` and `*.dll`) installed
+    /// into the `lib`, `lib64` and `bin` directories of the install prefix to
+    /// `dir` after the build, e.g. so they can be found at runtime next to the
+    /// final binary.
+    ///
+    /// Symlinks between versioned shared libraries (`libfoo.so ->
+    /// libfoo.so.1`) are recreated rather than copied.
+    pub fn copy_shared_libs_to<P: AsRef<Path>>(&mut self, dir: P) -> &mut Config {
+        self.shared_libs_dir = Some(env::current_dir().unwrap().join(dir));
+        self
+    }
 
-        if self.verbose_cmake {
-            cmd.arg("-Wdev");
-            cmd.arg("--debug-output");
-        }
+    /// Configures whether the settings this crate derives for cross compiling
+    /// (`CMAKE_SYSTEM_NAME`, the compilers, Apple SDK variables, ...) are
+    /// written to a generated toolchain file rather than passed as `-D` flags.
+    ///
+    /// Toolchain files are processed before the project's `project()` call and
+    /// also apply to cmake's `try_compile` checks, so this is more reliable.
+    /// It only applies when the target differs from the host and no
+    /// `CMAKE_TOOLCHAIN_FILE` was specified.
+    ///
+    /// This option defaults to `false`, and is turned on by `cross_defaults`.
+    pub fn generated_toolchain_file(&mut self, enable: bool) -> &mut Config {
+        self.generated_toolchain_file = enable;
+        self
+    }
 
-        cmd.arg(&self.path).current_dir(&build);
-        let mut is_ninja = false;
-        if let Some(ref generator) = self.generator {
-            is_ninja = generator.to_string_lossy().contains("Ninja");
+    /// Writes a cmake toolchain file capturing the settings this crate derives
+    /// for the configured target and returns its path.
+    ///
+    /// This is what `build` uses when cross compiling with
+    /// `generated_toolchain_file`, but it can also be called directly to
+    /// reuse the toolchain file elsewhere.
+    pub fn generate_toolchain_file(&self) -> PathBuf {
+        let target_triple = self.get_target_triple();
+        let host = self.get_host_triple();
+        let (c_compiler, cxx_compiler) = self.compilers(&target_triple, &host);
+        let build = self.build_dir();
+        if let Err(e) = fs::create_dir_all(&build) {
+            fail(&format!("failed to create {}: {}", build.display(), e));
         }
-        if target_triple.contains("windows-gnu") {
-            if host.contains("windows") {
-                // On MinGW we need to coerce cmake to not generate a visual
-                // studio build system but instead use makefiles that MinGW can
-                // use to build.
-                if self.generator.is_none() {
-                    // If make.exe isn't found, that means we may be using a MinGW
-                    // toolchain instead of a MSYS2 toolchain. If neither is found,
-                    // the build cannot continue.
-                    let has_msys2 = Command::new("make")
-                        .arg("--version")
-                        .output()
-                        .err()
-                        .map(|e| e.kind() != ErrorKind::NotFound)
-                        .unwrap_or(true);
-                    let has_mingw32 = Command::new("mingw32-make")
-                        .arg("--version")
-                        .output()
-                        .err()
-                        .map(|e| e.kind() != ErrorKind::NotFound)
-                        .unwrap_or(true);
+        let generator =
+            generator_arg(&self.extra_configure_args()).or_else(|| self.generator_name());
+        let defines = self.toolchain_defines(
+            &target_triple,
+            &host,
+            &c_compiler,
+            &cxx_compiler,
+            generator.as_deref(),
+        );
+        write_toolchain_file(&build, &target_triple, &defines)
+            .unwrap_or_else(|e| fail(&e.to_string()))
+    }
 
-                    let generator = match (has_msys2, has_mingw32) {
-                        (true, _) => "MSYS Makefiles",
-                        (false, true) => "MinGW Makefiles",
-                        (false, false) => fail("no valid generator found for GNU toolchain; MSYS or MinGW must be installed")
-                    };
+    /// Configures whether `cargo:rerun-if-changed` is printed for every file in
+    /// the project's source tree, so that the build script reruns whenever the
+    /// native sources change.
+    ///
+    /// Version control directories (`.git`, `.hg`, `.svn`), the output
+    /// directory and the Cargo target directory (`CARGO_TARGET_DIR`, or the
+    /// one `OUT_DIR` is in) are always skipped, see `rerun_exclude` to skip
+    /// more. As this prints a line for every file in the project, it's
+    /// opt-in; without it Cargo reruns the build script whenever a file in the
+    /// package changes.
+    ///
+    /// This also prints `cargo:rerun-if-env-changed` for the environment
+    /// variables this crate consults, such as `CMAKE`, `CMAKE_PREFIX_PATH`
+    /// and `TARGET`, and for `CMAKE_GENERATOR`, which cmake reads itself.
+    /// This includes the variables read before the option was turned on, such
+    /// as the `CARGO_FEATURE_*` ones of `map_feature`.
+    ///
+    /// This option defaults to `false`.
+    pub fn register_rerun_if_changed(&mut self, enable: bool) -> &mut Config {
+        self.rerun_if_changed = enable;
+        self
+    }
 
-                    cmd.arg("-G").arg(generator);
-                }
-            } else {
-                // If we're cross compiling onto windows, then set some
-                // variables which will hopefully get things to succeed. Some
-                // systems may need the `windres` or `dlltool` variables set, so
-                // set them if possible.
-                if !self.defined("CMAKE_SYSTEM_NAME") {
-                    cmd.arg("-DCMAKE_SYSTEM_NAME=Windows");
-                }
-                if !self.defined("CMAKE_RC_COMPILER") {
-                    let exe = find_exe(c_compiler.path());
-                    if let Some(name) = exe.file_name().unwrap().to_str() {
-                        let name = name.replace("gcc", "windres");
-                        let windres = exe.with_file_name(name);
-                        if windres.is_file() {
-                            let mut arg = OsString::from("-DCMAKE_RC_COMPILER=");
-                            arg.push(&windres);
-                            cmd.arg(arg);
-                        }
-                    }
-                }
-            }
-        } else if msvc {
-            // If we're on MSVC we need to be sure to use the right generator or
-            // otherwise we won't get 32/64 bit correct automatically.
-            // This also guarantees that NMake generator isn't chosen implicitly.
-            let using_nmake_generator;
-            if self.generator.is_none() {
-                cmd.arg("-G")
-                    .arg(self.visual_studio_generator(&target_triple));
-                using_nmake_generator = false;
-            } else {
-                using_nmake_generator = self.generator.as_ref().unwrap() == "NMake Makefiles";
-            }
-            if !is_ninja && !using_nmake_generator {
-                if target_triple.contains("x86_64") {
-                    cmd.arg("-Thost=x64");
-                    cmd.arg("-Ax64");
-                } else if target_triple.contains("thumbv7a") {
-                    cmd.arg("-Thost=x64");
-                    cmd.arg("-Aarm");
-                } else if target_triple.contains("aarch64") {
-                    cmd.arg("-Thost=x64");
-                    cmd.arg("-AARM64");
-                } else if target_triple.contains("i686") {
-                    use cc::windows_registry::{find_vs_version, VsVers};
-                    match find_vs_version() {
-                        Ok(VsVers::Vs16) => {
-                            // 32-bit x86 toolset used to be the default for all hosts,
-                            // but Visual Studio 2019 changed the default toolset to match the host,
-                            // so we need to manually override it for x86 targets
-                            cmd.arg("-Thost=x86");
-                            cmd.arg("-AWin32");
-                        }
-                        _ => {}
-                    };
-                } else {
-                    panic!("unsupported msvc target: {}", target_triple);
-                }
-            }
-        } else if target_triple.contains("redox") {
-            if !self.defined("CMAKE_SYSTEM_NAME") {
-                cmd.arg("-DCMAKE_SYSTEM_NAME=Generic");
-            }
-        } else if target_triple.contains("solaris") {
-            if !self.defined("CMAKE_SYSTEM_NAME") {
-                cmd.arg("-DCMAKE_SYSTEM_NAME=SunOS");
-            }
-        }
+    /// Excludes paths from the source tree walk done by
+    /// `register_rerun_if_changed`.
+    ///
+    /// `pattern` is either a path prefix, relative to the source directory, or
+    /// a glob using `*`, `?`, `**` and `[...]`. Globs containing a `/` are
+    /// matched against paths relative to the source directory, others against
+    /// file names, e.g. `*.o` or `tests/**/fixtures`.
+    pub fn rerun_exclude<P: AsRef<Path>>(&mut self, pattern: P) -> &mut Config {
+        self.rerun_excludes.push(pattern.as_ref().to_path_buf());
+        self
+    }
 
-        target.add_cmake_defines(&mut cmd, self);
+    /// Configures the project with the given preset, passing `--preset <name>`
+    /// to the generation step.
+    ///
+    /// The preset controls the generator, build type, toolchain and cache
+    /// variables, so `define`, `generator`, `profile` and the compilers and
+    /// flags this crate would pass are ignored, though `undefine` still
+    /// applies. The project is still
+    /// configured into this crate's build directory, which takes precedence
+    /// over the preset's `binaryDir`, and built and installed from there.
+    /// `configure_arg` and `initial_cache` settings are passed along.
+    ///
+    /// If the presets also define a build preset of the same name, the build
+    /// runs `cmake --build --preset <name>`, which then picks the
+    /// configuration, jobs and native tool options, and the install uses the
+    /// preset's configuration. Otherwise the build directory is built without
+    /// a `--config`, leaving the configuration to the configure preset. A
+    /// build preset whose configure preset declares a `binaryDir` isn't used,
+    /// as cmake would build that directory instead of this crate's.
+    ///
+    /// Presets require cmake 3.19 or later.
+    pub fn preset<T: AsRef<str>>(&mut self, name: T) -> &mut Config {
+        self.preset = Some(name.as_ref().to_string());
+        self
+    }
 
-        if let Some(ref generator) = self.generator {
-            cmd.arg("-G").arg(generator);
-        }
-        let profile = self.profile.clone().unwrap_or_else(|| {
-            // Automatically set the `CMAKE_BUILD_TYPE` if the user did not
-            // specify one.
+    /// Uses the presets in `path` rather than a `CMakePresets.json` next to
+    /// the project's top-level `CMakeLists.txt`.
+    ///
+    /// As cmake only looks for presets in the source directory, the file is
+    /// copied there for the duration of the build, as `CMakePresets.json` or,
+    /// if the project ships its own presets, `CMakeUserPresets.json`. The build
+    /// fails rather than overwrite an existing presets file.
+    pub fn presets_file<P: AsRef<Path>>(&mut self, path: P) -> &mut Config {
+        self.presets_file = Some(env::current_dir().unwrap().join(path));
+        self
+    }
 
-            // Determine Rust's profile, optimization level, and debug info:
-            #[derive(PartialEq)]
-            enum RustProfile {
-                Debug,
-                Release,
-            }
-            #[derive(PartialEq, Debug)]
-            enum OptLevel {
-                Debug,
-                Release,
-                Size,
-            }
+    /// Links the static library `name` built by the project into the final
+    /// artifact as a whole archive, keeping every object in it rather than
+    /// only those referenced, which is needed for libraries relying on static
+    /// constructors for registration or with circular dependencies.
+    ///
+    /// This prints `cargo:rustc-link-lib=static:+whole-archive=<name>` after a
+    /// successful build; rustc translates it into the platform's equivalent of
+    /// `-Wl,--whole-archive` (`-force_load` on macOS, `/WHOLEARCHIVE` with
+    /// MSVC). The library's directory still needs to be added to the link
+    /// search path.
+    pub fn link_lib_whole_archive(&mut self, name: &str) -> &mut Config {
+        self.whole_archive_libs.push(name.to_string());
+        self
+    }
 
-            let rust_profile = match &getenv_unwrap("PROFILE")[..] {
-                "debug" => RustProfile::Debug,
-                "release" | "bench" => RustProfile::Release,
-                unknown => {
-                    eprintln!(
-                        "Warning: unknown Rust profile={}; defaulting to a release build.",
-                        unknown
-                    );
-                    RustProfile::Release
-                }
-            };
+    /// Configures whether cmake's configure-time checks build static libraries
+    /// rather than executables, by setting
+    /// `CMAKE_TRY_COMPILE_TARGET_TYPE=STATIC_LIBRARY`.
+    ///
+    /// This is typically needed when cross compiling for bare-metal or
+    /// embedded targets, where the test executables can't be linked without a
+    /// custom linker script or startup code.
+    ///
+    /// This option defaults to `false`.
+    pub fn try_compile_static(&mut self, enable: bool) -> &mut Config {
+        self.try_compile_static = enable;
+        self
+    }
 
-            let opt_level = match &getenv_unwrap("OPT_LEVEL")[..] {
-                "0" => OptLevel::Debug,
-                "1" | "2" | "3" => OptLevel::Release,
-                "s" | "z" => OptLevel::Size,
-                unknown => {
-                    let default_opt_level = match rust_profile {
-                        RustProfile::Debug => OptLevel::Debug,
-                        RustProfile::Release => OptLevel::Release,
-                    };
-                    eprintln!(
-                        "Warning: unknown opt-level={}; defaulting to a {:?} build.",
-                        unknown, default_opt_level
-                    );
-                    default_opt_level
-                }
-            };
+    /// Adds an argument to the linker invocation of the Rust crate being built.
+    ///
+    /// This prints `cargo:rustc-link-arg=<arg>` after a successful build. It
+    /// affects the final Rust artifact only; use `CMAKE_<TYPE>_LINKER_FLAGS`
+    /// via `define` to pass flags to the linker cmake itself runs.
+    pub fn link_arg(&mut self, arg: &str) -> &mut Config {
+        self.link_args.push(arg.to_string());
+        self
+    }
 
-            let debug_info: bool = match &getenv_unwrap("DEBUG")[..] {
-                "false" => false,
-                "true" => true,
-                unknown => {
-                    eprintln!("Warning: unknown debug={}; defaulting to `true`.", unknown);
-                    true
-                }
-            };
+    /// Configures whether cmake writes a `compile_commands.json` compilation
+    /// database to the build directory, by setting
+    /// `CMAKE_EXPORT_COMPILE_COMMANDS=ON`.
+    ///
+    /// This option defaults to `false`. See also `merge_compile_commands`.
+    pub fn export_compile_commands(&mut self, export: bool) -> &mut Config {
+        self.export_compile_commands = export;
+        self
+    }
 
-            match (opt_level, debug_info) {
-                (OptLevel::Debug, _) => "Debug",
-                (OptLevel::Release, false) => "Release",
-                (OptLevel::Release, true) => "RelWithDebInfo",
-                (OptLevel::Size, _) => "MinSizeRel",
-            }
-            .to_string()
-        });
-        for &(ref k, ref v) in &self.defines {
-            let mut os = OsString::from("-D");
-            os.push(k);
-            os.push("=");
-            os.push(v);
-            cmd.arg(os);
-        }
+    /// Prevents `find_package(<pkg>)` from finding the package, by setting
+    /// `CMAKE_DISABLE_FIND_PACKAGE_<pkg>=ON`.
+    ///
+    /// This is useful to force a project to build a bundled copy of a
+    /// dependency instead of picking up the one installed on the system.
+    pub fn disable_find_package(&mut self, pkg: &str) -> &mut Config {
+        self.define(format!("CMAKE_DISABLE_FIND_PACKAGE_{}", pkg), "ON")
+    }
 
-        if !self.defined("CMAKE_INSTALL_PREFIX") {
-            let mut dstflag = OsString::from("-DCMAKE_INSTALL_PREFIX=");
-            dstflag.push(&dst);
-            cmd.arg(dstflag);
-        }
+    /// Makes `find_package(<pkg>)` fail the configuration if the package
+    /// isn't found, by setting `CMAKE_REQUIRE_FIND_PACKAGE_<pkg>=ON`.
+    ///
+    /// This requires cmake 3.22 or later.
+    pub fn require_find_package(&mut self, pkg: &str) -> &mut Config {
+        self.define(format!("CMAKE_REQUIRE_FIND_PACKAGE_{}", pkg), "ON")
+    }
 
-        let build_type = self
+    /// Returns the native build tool (make, ninja, msbuild, ...) that builds
+    /// this project.
+    ///
+    /// Once the project has been configured this is the `CMAKE_MAKE_PROGRAM`
+    /// recorded in the build directory's cache. Before that, it's the tool set
+    /// with `make_program`, or else guessed from the generator set with
+    /// `generator`, if any.
+    pub fn native_tool(&self) -> Option<PathBuf> {
+        if let Some(program) = cache_value(&self.build_dir(), "CMAKE_MAKE_PROGRAM") {
+            return Some(PathBuf::from(program));
+        }
+        if let Some((_, program)) = self
             .defines
             .iter()
-            .find(|&&(ref a, _)| a == "CMAKE_BUILD_TYPE")
-            .map(|x| x.1.to_str().unwrap())
-            .unwrap_or(&profile);
-        let build_type_upcase = build_type
-            .chars()
-            .flat_map(|c| c.to_uppercase())
-            .collect::<String>();
-
+            .find(|(k, _)| define_name(k) == "CMAKE_MAKE_PROGRAM")
         {
-            // let cmake deal with optimization/debuginfo
-            let skip_arg = |arg: &OsStr| match arg.to_str() {
-                Some(s) => s.starts_with("-O") || s.starts_with("/O") || s == "-g",
-                None => false,
-            };
-            let mut set_compiler = |kind: &str, compiler: &cc::Tool, extra: &OsString| {
-                let mut add_compiler_flags = |flag_var_name: &str| {
-                    if !self.defined(&flag_var_name) {
-                        let mut compiler_flags = OsString::new();
-                        for arg in compiler.args() {
-                            if skip_arg(arg) {
-                                continue;
-                            }
-                            compiler_flags.push(" ");
-                            compiler_flags.push(arg);
-                        }
-                        target.filter_compiler_args(&mut compiler_flags);
+            return Some(PathBuf::from(program));
+        }
+        let generator = self.generator_name()?;
+        let generator = generator.to_string_lossy();
+        let tool = if generator.contains("Ninja") {
+            "ninja"
+        } else if generator.contains("Visual Studio") {
+            "msbuild"
+        } else if generator.contains("NMake") {
+            "nmake"
+        } else if generator.contains("MinGW") {
+            "mingw32-make"
+        } else if generator.contains("Makefiles") {
+            "make"
+        } else {
+            return None;
+        };
+        Some(PathBuf::from(tool))
+    }
 
-                        // We want to filter compiler args from cc-rs, but not user-supplied ones,
-                        // so we add user-supplied ones after we filter.
-                        compiler_flags.push(extra);
+    /// Returns the path of the `CMakeCache.txt` in this project's build
+    /// directory, see `read_cache`.
+    ///
+    /// The file only exists once the project has been configured, i.e. after
+    /// `build` or `try_configure`.
+    pub fn cache_file_path(&self) -> PathBuf {
+        self.build_dir().join("CMakeCache.txt")
+    }
 
-                        let mut flagsflag = OsString::from("-D");
-                        flagsflag.push(flag_var_name);
-                        flagsflag.push("=");
-                        flagsflag.push(compiler_flags);
+    /// Removes the cmake build directory this configuration builds in, taking
+    /// `out_dir` and `build_dir_name` into account, so that the next build
+    /// configures the project from scratch.
+    ///
+    /// A directory given to `use_existing_build_dir` is left alone, as it is
+    /// never cleared by this crate.
+    pub fn clean(&self) -> io::Result<()> {
+        if self.existing_build_dir.is_some() {
+            return Ok(());
+        }
+        remove_build_dir(&self.build_dir())
+    }
 
-                        cmd.arg(flagsflag);
-                    }
-                };
+    /// Configures whether `--compile-no-warning-as-error` is passed to cmake,
+    /// overriding a project that sets `CMAKE_COMPILE_WARNING_AS_ERROR`.
+    ///
+    /// The flag requires cmake 3.24 or later; with older versions a warning
+    /// is printed and the flag is left out.
+    ///
+    /// This option defaults to `false`.
+    pub fn no_warning_as_error(&mut self, enable: bool) -> &mut Config {
+        self.no_warning_as_error = enable;
+        self
+    }
 
-                let flag_var = format!("CMAKE_{}_FLAGS", kind);
-                let tool_var = format!("CMAKE_{}_COMPILER", kind);
-                add_compiler_flags(&flag_var);
-
-                // The visual studio generator apparently doesn't respect
-                // `CMAKE_C_FLAGS` but does respect `CMAKE_C_FLAGS_RELEASE` and
-                // such. We need to communicate /MD vs /MT, so set those vars
-                // here.
-                //
-                // Note that for other generators, though, this *overrides*
-                // things like the optimization flags, which is bad.
-                if self.generator.is_none() && msvc {
-                    let flag_var_alt = format!("CMAKE_{}_FLAGS_{}", kind, build_type_upcase);
-                    add_compiler_flags(&flag_var_alt);
-                }
-
-                // Apparently cmake likes to have an absolute path to the
-                // compiler as otherwise it sometimes thinks that this variable
-                // changed as it thinks the found compiler, /usr/bin/cc,
-                // differs from the specified compiler, cc. Not entirely sure
-                // what's up, but at least this means cmake doesn't get
-                // confused?
-                //
-                // Also specify this on Windows only if we use MSVC with Ninja,
-                // as it's not needed for MSVC with Visual Studio generators and
-                // for MinGW it doesn't really vary.
-                if !self.defined("CMAKE_TOOLCHAIN_FILE")
-                    && !self.defined(&tool_var)
-                    && (env::consts::FAMILY != "windows" || (msvc && is_ninja))
-                {
-                    let mut ccompiler = OsString::from("-D");
-                    ccompiler.push(&tool_var);
-                    ccompiler.push("=");
-                    ccompiler.push(find_exe(compiler.path()));
-                    #[cfg(windows)]
-                    {
-                        // CMake doesn't like unescaped `\`s in compiler paths
-                        // so we either have to escape them or replace with `/`s.
-                        use std::os::windows::ffi::{OsStrExt, OsStringExt};
-                        let wchars = ccompiler
-                            .encode_wide()
-                            .map(|wchar| {
-                                if wchar == b'\\' as u16 {
-                                    '/' as u16
-                                } else {
-                                    wchar
-                                }
-                            })
-                            .collect::<Vec<_>>();
-                        ccompiler = OsString::from_wide(&wchars);
-                    }
-                    cmd.arg(ccompiler);
-                }
-            };
+    /// Sets the permissions `install` gives to directories it creates without
+    /// explicit permissions, via `CMAKE_INSTALL_DEFAULT_DIRECTORY_PERMISSIONS`.
+    ///
+    /// `perms` is a cmake list of permissions, for example
+    /// `"OWNER_READ;OWNER_WRITE;OWNER_EXECUTE"`. This requires cmake 3.11 or
+    /// later.
+    pub fn install_default_permissions(&mut self, perms: &str) -> &mut Config {
+        self.install_default_permissions = Some(perms.to_string());
+        self
+    }
 
-            set_compiler("C", &c_compiler, &self.cflags);
-            set_compiler("CXX", &cxx_compiler, &self.cxxflags);
-            set_compiler("ASM", &asm_compiler, &self.asmflags);
-        }
+    /// Configures whether the `install` target skips building all targets
+    /// first, by setting `CMAKE_SKIP_INSTALL_ALL_DEPENDENCY=ON`.
+    ///
+    /// Together with `build_target_sequence` this builds and installs only
+    /// the targets that are needed, e.g. `["foo", "install"]`.
+    ///
+    /// This option defaults to `false`.
+    pub fn skip_install_all_dependency(&mut self, enable: bool) -> &mut Config {
+        self.skip_install_all_dependency = enable;
+        self
+    }
 
-        if !self.defined("CMAKE_BUILD_TYPE") {
-            cmd.arg(&format!("-DCMAKE_BUILD_TYPE={}", profile));
-        }
+    /// Configures whether cmake prints every location searched by the
+    /// `find_*` commands, by setting `CMAKE_FIND_DEBUG_MODE=ON`.
+    ///
+    /// This requires cmake 3.17 or later; with older versions a warning is
+    /// printed and the option is ignored.
+    ///
+    /// This option defaults to `false`.
+    pub fn find_debug(&mut self, enable: bool) -> &mut Config {
+        self.find_debug = enable;
+        self
+    }
 
-        if self.verbose_make {
-            cmd.arg("-DCMAKE_VERBOSE_MAKEFILE:BOOL=ON");
-        }
+    /// Selects which kinds of compiler flags determined by the `cc` crate are
+    /// forwarded to cmake through `CMAKE_<LANG>_FLAGS`.
+    ///
+    /// Optimization and debug info flags are never forwarded since cmake
+    /// derives those from the build type. Flags added with `cflag` and
+    /// friends are always passed along.
+    ///
+    /// This option defaults to `CcFlagKinds::ALL`.
+    pub fn forward_cc_flags(&mut self, which: CcFlagKinds) -> &mut Config {
+        self.cc_flag_kinds = which;
+        self
+    }
+
+    /// Configures whether the time taken by the configure and build steps is
+    /// reported, as a `cargo:warning=cmake timings: configure=<t> build=<t>`
+    /// line.
+    ///
+    /// This option defaults to `false`.
+    pub fn emit_timings(&mut self, enable: bool) -> &mut Config {
+        self.emit_timings = enable;
+        self
+    }
+
+    /// Sets the directory under which `ExternalProject_Add` stages the
+    /// dependencies it builds.
+    ///
+    /// This sets the `EP_PREFIX` directory property of the top-level project
+    /// through `CMAKE_PROJECT_INCLUDE_BEFORE` (cmake 3.15 or later), and also
+    /// defines an `EP_PREFIX` cache variable for projects that refer to it
+    /// directly. Unlike the build directory, this directory is left alone
+    /// when the build directory is cleaned, so sub-builds aren't repeated.
+    pub fn external_project_prefix<P: AsRef<Path>>(&mut self, dir: P) -> &mut Config {
+        self.external_project_prefix = Some(env::current_dir().unwrap().join(dir));
+        self
+    }
+
+    /// Passes the Rust target triple being built for to cmake as the variable
+    /// `cmake_var`.
+    ///
+    /// The triple is the one set with `target`, falling back to `$TARGET`,
+    /// and is resolved when `build` runs.
+    pub fn define_target_triple(&mut self, cmake_var: &str) -> &mut Config {
+        self.target_triple_var = Some(cmake_var.to_string());
+        self
+    }
 
-        if !self.defined("CMAKE_TOOLCHAIN_FILE") {
-            if let Ok(s) = env::var("CMAKE_TOOLCHAIN_FILE") {
-                cmd.arg(&format!("-DCMAKE_TOOLCHAIN_FILE={}", s));
+    /// Looks up the value a cache variable such as an `option()` gets when the
+    /// project is configured, without building it.
+    ///
+    /// This configures the project once into a scratch directory with the
+    /// defines set so far, and reads the variable from the resulting cache;
+    /// later calls on the same `Config` reuse that configuration. `None` is
+    /// returned if the project doesn't define the variable or the probe
+    /// configuration fails.
+    pub fn probe_option(&mut self, name: &str) -> Option<String> {
+        if self.probe_dir.is_none() {
+            let dir = self.dst_dir().join("probe");
+            let _ = fs::remove_dir_all(&dir);
+            if let Err(e) = fs::create_dir_all(&dir) {
+                fail(&format!("failed to create {}: {}", dir.display(), e));
             }
-        }
 
-        for &(ref k, ref v) in c_compiler.env().iter().chain(&self.env) {
-            if target.should_exclude_env_var(k, v) {
-                continue;
+            let target_triple = self.get_target_triple();
+            let host = self.get_host_triple();
+            let (c_compiler, cxx_compiler) = self.compilers(&target_triple, &host);
+            let mut cmd = Command::new(self.cmake_executable());
+            cmd.arg(self.source_dir()).current_dir(&dir);
+            let generator = self.generator_name();
+            if let Some(ref generator) = generator {
+                cmd.arg("-G").arg(generator);
+            }
+            for (k, v) in self.toolchain_defines(
+                &target_triple,
+                &host,
+                &c_compiler,
+                &cxx_compiler,
+                generator.as_deref(),
+            ) {
+                cmd.arg(define_arg(&k, &v));
+            }
+            for (k, v) in self.ordered_defines() {
+                cmd.arg(define_arg(&k.to_string_lossy(), v));
+            }
+            match cmd.output() {
+                Ok(ref output) if output.status.success() => {}
+                _ => self.print(&format!(
+                    "cargo:warning=failed to configure {} to probe its options",
+                    self.path.display()
+                )),
             }
-            cmd.env(k, v);
+            self.probe_dir = Some(dir);
         }
+        cache_value(self.probe_dir.as_ref().unwrap(), name)
+    }
+
+    /// Configures whether MSVC targets without an explicit `generator` prefer
+    /// a command-line generator over the Visual Studio one.
+    ///
+    /// When enabled, `Ninja` is used if `ninja` is on `PATH`, otherwise
+    /// `NMake Makefiles JOM` if `jom` is, falling back to the Visual Studio
+    /// generator. These generators take the target architecture from the
+    /// compiler environment, so the build should run from a developer command
+    /// prompt (or with `vcvars` applied) for the intended architecture.
+    ///
+    /// This option defaults to `false`.
+    pub fn msvc_prefer_makefiles(&mut self, enable: bool) -> &mut Config {
+        self.msvc_prefer_makefiles = enable;
+        self
+    }
+
+    /// Configures whether compiler warnings are treated as errors.
+    ///
+    /// With cmake 3.24 or later this sets `CMAKE_COMPILE_WARNING_AS_ERROR=ON`,
+    /// which applies to every target honoring the `COMPILE_WARNING_AS_ERROR`
+    /// property. Older versions get `-Werror` (or `/WX` for MSVC-like
+    /// compilers) added to the C and C++ flags, which also affects any
+    /// third-party code built as part of the project. This is the inverse of
+    /// `no_warning_as_error`.
+    ///
+    /// This option defaults to `false`.
+    pub fn warnings_as_errors(&mut self, enable: bool) -> &mut Config {
+        self.warnings_as_errors = enable;
+        self
+    }
+
+    /// Parses the pkg-config file `<name>.pc` installed by the project.
+    ///
+    /// This should be called after `build`; the file is looked up in the
+    /// `lib/pkgconfig`, `lib64/pkgconfig` and `share/pkgconfig` directories
+    /// of the install prefix. Variables such as `${prefix}` are substituted,
+    /// and `None` is returned if no such file was installed.
+    pub fn parse_pc_file(&self, name: &str) -> Option<PkgConfig> {
+        let dst = self.dst_dir();
+        let file = ["lib", "lib64", "share"]
+            .iter()
+            .map(|dir| dst.join(dir).join("pkgconfig").join(format!("{}.pc", name)))
+            .find(|file| file.is_file())?;
+        let contents = fs::read_to_string(&file).ok()?;
+        Some(parse_pc(&contents))
+    }
+
+    /// Builds a universal macOS binary containing code for each of `archs`,
+    /// e.g. `&["arm64", "x86_64"]`, through `CMAKE_OSX_ARCHITECTURES`.
+    ///
+    /// The architecture flags the `cc` crate derives from the Rust target are
+    /// not forwarded in this mode, as they would restrict the compiler to a
+    /// single architecture. Universal output requires a macOS target and the
+    /// Xcode, Ninja or a Makefiles generator; `build` panics otherwise.
+    pub fn universal_macos(&mut self, archs: &[&str]) -> &mut Config {
+        self.osx_archs(archs)
+    }
+
+    /// Sets the architectures of Apple targets, passed to cmake as the
+    /// `;`-separated `CMAKE_OSX_ARCHITECTURES`, see `universal_macos`.
+    ///
+    /// Without this the single architecture matching the target is used, e.g.
+    /// `arm64` for `aarch64-apple-darwin`.
+    pub fn osx_archs<I, S>(&mut self, archs: I) -> &mut Config
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.universal_archs = archs.into_iter().map(|a| a.as_ref().to_string()).collect();
+        self
+    }
+
+    /// Configures whether symbols are hidden by default, by setting
+    /// `CMAKE_C_VISIBILITY_PRESET=hidden`, `CMAKE_CXX_VISIBILITY_PRESET=hidden`
+    /// and `CMAKE_VISIBILITY_INLINES_HIDDEN=ON`.
+    ///
+    /// Hiding the internal symbols of static libraries linked into Rust often
+    /// fixes duplicate symbol errors when linking several C++ dependencies,
+    /// and reduces binary size. Projects that rely on default visibility to
+    /// export their API may break, so this option defaults to `false`.
+    pub fn hidden_visibility(&mut self, enable: bool) -> &mut Config {
+        self.hidden_visibility = enable;
+        self
+    }
+
+    /// Maps the Cargo feature `cargo_feature` to the cmake option
+    /// `cmake_option`, which is set to `ON` if the feature is enabled and to
+    /// `OFF` otherwise.
+    ///
+    /// Whether the feature is enabled is read from the corresponding
+    /// `CARGO_FEATURE_<NAME>` variable, with the feature name uppercased and
+    /// `-` replaced by `_`.
+    pub fn map_feature(&mut self, cargo_feature: &str, cmake_option: &str) -> &mut Config {
+        let var = format!(
+            "CARGO_FEATURE_{}",
+            cargo_feature.to_uppercase().replace('-', "_")
+        );
+        let value = if self.getenv_os(&var).is_some() {
+            "ON"
+        } else {
+            "OFF"
+        };
+        self.define(cmake_option, value)
+    }
+
+    /// Sets a time limit for the build system generation step.
+    ///
+    /// If cmake runs for longer it is killed along with any processes it
+    /// started, and `try_build` returns `Error::Timeout` (`build` panics).
+    pub fn configure_timeout(&mut self, timeout: Duration) -> &mut Config {
+        self.configure_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets a time limit for the build step, see `configure_timeout`.
+    ///
+    /// When building several targets with `build_target_sequence`, the limit
+    /// applies to each of them separately.
+    pub fn build_timeout(&mut self, timeout: Duration) -> &mut Config {
+        self.build_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets up the install so the installed tree keeps working when moved.
+    ///
+    /// Installed binaries get an RPATH relative to their own location
+    /// (`$ORIGIN/../lib`, or `@loader_path/../lib` with `@rpath` install names
+    /// on Apple targets) instead of absolute paths into the build tree. Files
+    /// the project generates itself, such as package config or pkg-config
+    /// files, may still contain the absolute install prefix unless the
+    /// project takes care to make them relocatable.
+    pub fn relocatable_install(&mut self) -> &mut Config {
+        self.relocatable_install = true;
+        self
+    }
+
+    /// Sets the linker used for the executables and shared libraries the
+    /// project links, overriding the one derived from Rust's configuration
+    /// (see `rust_linker`).
+    pub fn linker<P: AsRef<Path>>(&mut self, path: P) -> &mut Config {
+        self.linker = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Configures whether the linker Cargo uses for the Rust crate, from
+    /// `RUSTC_LINKER` or `CARGO_TARGET_<TRIPLE>_LINKER`, is also used for the
+    /// project's own links.
+    ///
+    /// For MSVC targets the linker becomes `CMAKE_LINKER`. Elsewhere, linkers
+    /// such as `ld.lld`, `mold` or `ld.gold` are selected with `-fuse-ld=` in
+    /// the initial linker flags; a compiler driver such as `gcc` is left
+    /// alone, since cmake links through the C/C++ compiler already. Static
+    /// libraries aren't linked, so pure static builds are unaffected.
+    ///
+    /// This option defaults to `true`.
+    pub fn rust_linker(&mut self, enable: bool) -> &mut Config {
+        self.rust_linker = enable;
+        self
+    }
+
+    /// Configures whether the precompiled headers a project declares with
+    /// `target_precompile_headers` are used, through
+    /// `CMAKE_DISABLE_PRECOMPILE_HEADERS` (cmake 3.16 or later).
+    ///
+    /// Precompiled headers are driven by the project, so enabling them has no
+    /// effect on projects that don't use them; some projects expose their own
+    /// option for it instead, which can be set with `define`. The compiler
+    /// flags passed by this crate are the same for every source file of a
+    /// language and only change when the configuration does, so they don't
+    /// invalidate precompiled headers between incremental builds.
+    ///
+    /// By default the project's own setting is left alone.
+    pub fn pch(&mut self, enable: bool) -> &mut Config {
+        self.pch = Some(enable);
+        self
+    }
+
+    /// Writes every `cargo:` directive this crate emits during the build to
+    /// the file at `path`, one per line, in addition to printing them.
+    ///
+    /// The file is written when the build finishes, also when it fails, and
+    /// includes the directives suppressed by `cargo_metadata(false)`.
+    pub fn dump_cargo_directives<P: AsRef<Path>>(&mut self, path: P) -> &mut Config {
+        self.cargo_directives_file = Some(env::current_dir().unwrap().join(path));
+        self
+    }
+
+    /// Configures whether the source tree is copied into the output directory
+    /// and configured from there, for projects that write into their source
+    /// directory while being configured or built.
+    ///
+    /// This makes such projects buildable from read-only locations such as
+    /// the Cargo registry. Only files that changed since the previous build
+    /// are copied again, symlinks are recreated rather than followed, and
+    /// `register_rerun_if_changed` keeps watching the original tree.
+    ///
+    /// This option defaults to `false`.
+    pub fn copy_source_to_outdir(&mut self, enable: bool) -> &mut Config {
+        self.copy_source = enable;
+        self
+    }
+
+    /// Sets the number of parallel jobs used by the build step.
+    ///
+    /// This is passed to `cmake --build` as `--parallel <jobs>` (cmake 3.12+).
+    /// By default the job count is taken from Cargo's `NUM_JOBS`, falling back
+    /// to the number of logical CPUs. Without an explicit job count a Makefile
+    /// build joins Cargo's jobserver instead, when one is available.
+    pub fn parallel(&mut self, jobs: u32) -> &mut Config {
+        self.parallel = Some(jobs);
+        self
+    }
+
+    /// Applies a best-effort set of cross compilation settings when the target
+    /// differs from the host.
+    ///
+    /// In addition to what is always derived for the target, this sets
+    /// `CMAKE_SYSTEM_NAME` and `CMAKE_SYSTEM_PROCESSOR` from the target triple,
+    /// `CMAKE_AR` and `CMAKE_RANLIB` from the tools detected by the `cc` crate,
+    /// and the `CMAKE_FIND_ROOT_PATH_MODE_*` variables so that libraries and
+    /// headers are only searched for in the target's root. The settings go
+    /// through the generated toolchain file. Any of these variables passed to
+    /// `define` takes precedence.
+    pub fn cross_defaults(&mut self) -> &mut Config {
+        self.cross_defaults = true;
+        self.generated_toolchain_file = true;
+        self
+    }
+
+    /// Sets the cmake toolchain file used for the build, passed as
+    /// `CMAKE_TOOLCHAIN_FILE` with an absolute path.
+    ///
+    /// A toolchain file takes precedence over the compilers this crate detects:
+    /// `CMAKE_C_COMPILER`, `CMAKE_CXX_COMPILER` and `CMAKE_ASM_COMPILER` are no
+    /// longer passed, and no toolchain file is generated when cross compiling.
+    /// Compilers passed to `define` explicitly are still forwarded.
+    pub fn define_toolchain_file<P: AsRef<Path>>(&mut self, path: P) -> &mut Config {
+        let path = env::current_dir().unwrap().join(path);
+        self.define("CMAKE_TOOLCHAIN_FILE", cmake_path(path.as_os_str()))
+    }
+
+    /// Sets the native build tool the generator uses, passed as
+    /// `CMAKE_MAKE_PROGRAM`, e.g. a `ninja` binary that isn't on `PATH`.
+    pub fn make_program<P: AsRef<OsStr>>(&mut self, program: P) -> &mut Config {
+        self.define("CMAKE_MAKE_PROGRAM", program)
+    }
+
+    /// Configures whether the `-D` definitions are written to an initial cache
+    /// script passed with `-C`, rather than onto the command line, to stay
+    /// clear of command line length limits.
+    ///
+    /// Each definition becomes a `set(<name> <value> CACHE <type> "" FORCE)`,
+    /// which overrides the cache just like `-D` does. By default this is only
+    /// done on Windows, whose command lines are the most limited, when there
+    /// are more than 100 definitions or they add up to more than 8 KiB.
+    pub fn defines_via_cache_file(&mut self, enable: bool) -> &mut Config {
+        self.defines_via_cache_file = Some(enable);
+        self
+    }
+
+    /// Configures whether the compilers found by the `cc` crate are left out,
+    /// letting cmake detect the compilers itself.
+    ///
+    /// When enabled `CMAKE_C_COMPILER`, `CMAKE_CXX_COMPILER` and
+    /// `CMAKE_ASM_COMPILER` aren't passed (nor the LTO archivers matching
+    /// them, see `lto`), while flags and defines still are. This is
+    /// unnecessary with `define_toolchain_file`, which already leaves the
+    /// compilers to the toolchain file.
+    ///
+    /// This option defaults to `false`.
+    pub fn no_default_compiler(&mut self, enable: bool) -> &mut Config {
+        self.no_default_compiler = enable;
+        self
+    }
+
+    /// Configures whether the directories of a sysroot given as `CMAKE_SYSROOT`
+    /// are added to cmake's search paths.
+    ///
+    /// When enabled the sysroot is added to `CMAKE_FIND_ROOT_PATH`, its
+    /// `usr/include` and `usr/lib` directories, along with their multiarch
+    /// variants such as `usr/lib/aarch64-linux-gnu`, to `CMAKE_INCLUDE_PATH`
+    /// and `CMAKE_LIBRARY_PATH`. Variables passed to `define` are left alone.
+    ///
+    /// This option defaults to `true`.
+    pub fn sysroot_search_paths(&mut self, enable: bool) -> &mut Config {
+        self.sysroot_search_paths = enable;
+        self
+    }
+
+    /// Points the cache variable `cmake_var` at a tool built for the host, e.g.
+    /// `Protobuf_PROTOC_EXECUTABLE`, so that a cross build uses it instead of
+    /// building a version of the tool that can't run on the host.
+    ///
+    /// The path is made absolute relative to the current directory.
+    pub fn import_host_executable(&mut self, cmake_var: &str, path: &Path) -> &mut Config {
+        let path = env::current_dir().unwrap().join(path);
+        self.define(cmake_var, path)
+    }
+
+    /// Returns the output of `cmake --system-information`, describing the
+    /// platform, the default generator and the detected compilers, e.g. for
+    /// bug reports.
+    ///
+    /// This runs in a scratch directory and returns `None` if cmake can't be
+    /// run or fails.
+    pub fn system_information(&self) -> Option<String> {
+        let dir = self.dst_dir().join("system-information");
+        fs::create_dir_all(&dir).ok()?;
+        let output = Command::new(self.cmake_executable())
+            .arg("--system-information")
+            .current_dir(&dir)
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        Some(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// Configures whether the project is built with link time optimization,
+    /// through `CMAKE_INTERPROCEDURAL_OPTIMIZATION`.
+    ///
+    /// Static libraries of LTO objects need an archiver that understands them,
+    /// so `CMAKE_<LANG>_COMPILER_AR` and `CMAKE_<LANG>_COMPILER_RANLIB` are
+    /// set to the matching `gcc-ar`/`gcc-ranlib` or `llvm-ar`/`llvm-ranlib`
+    /// next to the compiler, if found. Pass these variables to `define` to
+    /// choose the tools yourself.
+    ///
+    /// This option defaults to `false`.
+    pub fn lto(&mut self, enable: bool) -> &mut Config {
+        self.lto = enable;
+        self
+    }
+
+    /// Sets a command that prepares the source tree before cmake configures
+    /// it, e.g. a `./configure` script generating the `CMakeLists.txt`.
+    ///
+    /// The command runs in the source directory on every build, unless its
+    /// working directory was set explicitly. If the source directory is
+    /// read-only it's copied to the output directory first, as with
+    /// `copy_source_to_outdir`, and the command runs in the copy. The program
+    /// and any arguments naming files in the source tree are registered with
+    /// `cargo:rerun-if-changed`.
+    pub fn bootstrap_command(&mut self, cmd: Command) -> &mut Config {
+        self.bootstrap = Some(cmd);
+        self
+    }
+
+    /// Configures whether files installed by a previous build that the current
+    /// one no longer installs are removed from the install prefix.
+    ///
+    /// The files are found by comparing cmake's `install_manifest.txt` from
+    /// before and after the install step. Only files inside the install
+    /// prefix are removed, along with directories left empty by that.
+    ///
+    /// This option defaults to `false`.
+    pub fn prune_stale_installs(&mut self, enable: bool) -> &mut Config {
+        self.prune_stale_installs = enable;
+        self
+    }
+
+    /// Configures whether position independent code is generated, through
+    /// `CMAKE_POSITION_INDEPENDENT_CODE`.
+    ///
+    /// Static libraries linked into a Rust `cdylib` need this on most
+    /// platforms. By default the variable isn't passed, leaving it to the
+    /// project; the flags taken from the `cc` crate already include `-fPIC`
+    /// where it's needed.
+    pub fn pic(&mut self, enable: bool) -> &mut Config {
+        self.pic = Some(enable);
+        self
+    }
+
+    /// Builds every executable and library target of the project, including
+    /// those excluded from the default target such as tests or examples,
+    /// before the build target.
+    ///
+    /// The targets are listed through cmake's file API, which needs cmake 3.15
+    /// or later; with older versions a warning is printed and only the build
+    /// target is built.
+    pub fn build_all_including_excluded(&mut self) -> &mut Config {
+        self.build_all_targets = true;
+        self
+    }
+
+    /// Configures whether the project's `message()` output is prefixed with
+    /// its context, by setting `CMAKE_MESSAGE_CONTEXT_SHOW=ON`.
+    ///
+    /// This requires cmake 3.17 or later; with older versions a warning is
+    /// printed and the option is ignored.
+    ///
+    /// This option defaults to `false`.
+    pub fn message_context(&mut self, enable: bool) -> &mut Config {
+        self.message_context = enable;
+        self
+    }
+
+    /// Marks the project as only installing headers, e.g. interface libraries
+    /// consumed through `bindgen`.
+    ///
+    /// No `cargo:rustc-link-search` directives are printed for the install
+    /// prefix; instead `cargo:include=<prefix>/include` is printed so that
+    /// dependent build scripts can find the headers through
+    /// `DEP_<name>_INCLUDE`.
+    pub fn header_only(&mut self) -> &mut Config {
+        self.header_only = true;
+        self
+    }
+
+    /// Configures whether the warnings cmake prints while configuring, such as
+    /// `CMake Warning` and `CMake Deprecation Warning` messages, are repeated
+    /// as `cargo:warning` so that cargo shows them.
+    ///
+    /// The configure output is captured to find the warnings, and printed
+    /// once the step finishes unless it's hidden anyway, see
+    /// `capture_output`.
+    ///
+    /// This option defaults to `false`.
+    pub fn surface_cmake_warnings(&mut self, enable: bool) -> &mut Config {
+        self.surface_cmake_warnings = enable;
+        self
+    }
+
+    /// Configures whether the output of the cmake invocations is captured
+    /// rather than streamed to the build script's output.
+    ///
+    /// When a captured invocation fails, the last lines it wrote to standard
+    /// error are included in the `Error::CommandFailed` returned by
+    /// `try_build`, and so in the panic message of `build`. Output is always
+    /// captured with `Verbosity::Quiet`.
+    ///
+    /// This option defaults to `false`.
+    pub fn capture_output(&mut self, enable: bool) -> &mut Config {
+        self.capture_output = enable;
+        self
+    }
+
+    /// Overrides the path printed as `cargo:root`, which dependent build
+    /// scripts see as `DEP_<name>_ROOT`.
+    ///
+    /// By default this is the install prefix. A relative path is taken
+    /// relative to the install prefix.
+    pub fn root_metadata_path<P: AsRef<Path>>(&mut self, path: P) -> &mut Config {
+        self.root_metadata_path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets the name of the cmake build directory, which is created inside the
+    /// output directory.
+    ///
+    /// This is useful to build the same project in several configurations
+    /// under a single `OUT_DIR`.
+    ///
+    /// This option defaults to `"build"`.
+    pub fn build_dir_name<P: AsRef<Path>>(&mut self, name: P) -> &mut Config {
+        self.build_dir_name = name.as_ref().to_path_buf();
+        self
+    }
+
+    /// Builds in `dir`, a build directory that was already configured, e.g. by
+    /// hand while working on the native project.
+    ///
+    /// The configure step is skipped entirely and the directory is never
+    /// cleared, so only `cmake --build` runs in it. The configuration is
+    /// trusted as-is: defines and flags set on this `Config` don't apply,
+    /// nothing is written to the directory before the build, and the
+    /// generator recorded in its cache is used.
+    pub fn use_existing_build_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Config {
+        self.existing_build_dir = Some(env::current_dir().unwrap().join(dir));
+        self
+    }
+
+    /// Sets the Android NDK used to build for `*-linux-android*` targets.
+    ///
+    /// For Android targets the NDK's `build/cmake/android.toolchain.cmake` is
+    /// passed as `CMAKE_TOOLCHAIN_FILE`, along with the `ANDROID_ABI` matching
+    /// the target and `ANDROID_PLATFORM` if an API level was set with
+    /// `android_api_level`. Without this the NDK is taken from the
+    /// `ANDROID_NDK_HOME` environment variable. Defines passed explicitly take
+    /// precedence.
+    pub fn android_ndk<P: AsRef<Path>>(&mut self, ndk: P) -> &mut Config {
+        self.android_ndk = Some(env::current_dir().unwrap().join(ndk));
+        self
+    }
+
+    /// Sets the Android API level to build for, passed as
+    /// `ANDROID_PLATFORM=android-<level>`, see `android_ndk`.
+    pub fn android_api_level(&mut self, level: u32) -> &mut Config {
+        self.android_api_level = Some(level);
+        self
+    }
+
+    /// Configures whether builds for `*-musl` targets link statically.
+    ///
+    /// When enabled, `BUILD_SHARED_LIBS` defaults to `OFF`, `find_library`
+    /// only looks for static archives and executables are linked with
+    /// `-static`. The musl compiler itself is picked by the `cc` crate, e.g.
+    /// `musl-gcc` or `x86_64-linux-musl-gcc`, and its sysroot is used as
+    /// `CMAKE_SYSROOT` if it reports one, see `musl_sysroot`.
+    ///
+    /// This option defaults to whether Rust links the C runtime statically,
+    /// i.e. the `crt-static` target feature, which is the default for musl
+    /// targets. It has no effect for other targets.
+    pub fn musl_static(&mut self, enable: bool) -> &mut Config {
+        self.musl_static = Some(enable);
+        self
+    }
+
+    /// Sets the musl sysroot passed as `CMAKE_SYSROOT` for `*-musl` targets,
+    /// instead of the one reported by the compiler's `-print-sysroot`.
+    pub fn musl_sysroot<P: AsRef<Path>>(&mut self, path: P) -> &mut Config {
+        self.musl_sysroot = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Passes `-l <load>` to make, which holds off starting new jobs while the
+    /// system load average is above `load`.
+    ///
+    /// This keeps parallel builds from overloading shared machines more
+    /// reliably than a fixed job count. Only make supports it, so it's
+    /// ignored with a warning for other generators.
+    pub fn max_load(&mut self, load: f32) -> &mut Config {
+        self.max_load = Some(load);
+        self
+    }
+
+    /// Sets a program such as `ccache` or `sccache` that compiler invocations
+    /// are run through, passed as `CMAKE_C_COMPILER_LAUNCHER` and
+    /// `CMAKE_CXX_COMPILER_LAUNCHER`.
+    ///
+    /// If this isn't called, the `CMAKE_COMPILER_LAUNCHER` environment
+    /// variable is used, or else `RUSTC_WRAPPER` if it points at `ccache` or
+    /// `sccache`.
+    pub fn compiler_launcher<T: AsRef<OsStr>>(&mut self, launcher: T) -> &mut Config {
+        self.compiler_launcher = Some(launcher.as_ref().to_owned());
+        self
+    }
+
+    /// Configures whether `--clean-first` is passed to `cmake --build`, so
+    /// the targets are rebuilt from scratch rather than incrementally.
+    ///
+    /// This option defaults to `false`.
+    pub fn clean_first(&mut self, enable: bool) -> &mut Config {
+        self.clean_first = enable;
+        self
+    }
+
+    /// Configures whether `build_libs` lists the static libraries that were
+    /// installed.
+    ///
+    /// This option defaults to `true`.
+    pub fn list_static_libs(&mut self, enable: bool) -> &mut Config {
+        self.list_static_libs = enable;
+        self
+    }
+
+    /// Configures whether `build_libs` lists the shared libraries that were
+    /// installed.
+    ///
+    /// This option defaults to `true`.
+    pub fn list_shared_libs(&mut self, enable: bool) -> &mut Config {
+        self.list_shared_libs = enable;
+        self
+    }
+
+    /// Sets the WASI SDK used to build for `wasm32-wasi*` targets.
+    ///
+    /// For WASI targets the SDK's `share/cmake/wasi-sdk.cmake` (or its
+    /// `-pthread` and `-p2` variants for the matching targets) is passed as
+    /// `CMAKE_TOOLCHAIN_FILE`, along with `WASI_SDK_PREFIX`, from which it
+    /// derives the clang toolchain and the sysroot. Without this the SDK is
+    /// taken from the `WASI_SDK_PATH` environment variable, and the build
+    /// fails if neither is set and no toolchain file was defined.
+    pub fn wasi_sdk<P: AsRef<Path>>(&mut self, sdk: P) -> &mut Config {
+        self.wasi_sdk = Some(sdk.as_ref().to_path_buf());
+        self
+    }
+
+    /// Writes a JSON summary of how the project was built to `path` once the
+    /// build finishes, for auditing and reproducing builds.
+    ///
+    /// The report is an object with these keys, and only ever gains new ones:
+    ///
+    /// * `version`: the schema version, currently `1`.
+    /// * `target`, `host`: the target and host triples.
+    /// * `cmake`: an object with the `path` and `version` of cmake.
+    /// * `generator`: the generator that was used.
+    /// * `build_type`: the `CMAKE_BUILD_TYPE` / configuration that was built.
+    /// * `compilers`: an object mapping `C` and `CXX` to objects with the
+    ///   `path` of the compiler and the `id` and `version` cmake detected.
+    /// * `defines`: an array of `[name, value]` pairs in the order they were
+    ///   passed to cmake, including the ones selecting the toolchain.
+    /// * `flags`: an object with the extra `c`, `cxx`, `asm` and `ld` flags.
+    ///
+    /// Values that aren't known are `null`.
+    pub fn write_build_report<P: AsRef<Path>>(&mut self, path: P) -> &mut Config {
+        self.build_report = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    // Simple heuristic to determine if we're cross-compiling using the Android
+    // NDK toolchain file.
+    fn uses_android_ndk(&self) -> bool {
+        // `ANDROID_ABI` is the only required flag:
+        // https://developer.android.com/ndk/guides/cmake#android_abi
+        self.defined("ANDROID_ABI")
+            && self.defines.iter().any(|(flag, value)| {
+                define_name(flag) == "CMAKE_TOOLCHAIN_FILE"
+                    && Path::new(value).file_name() == Some("android.toolchain.cmake".as_ref())
+            })
+    }
+
+    /// Run this configuration, compiling the library with all the configured
+    /// options.
+    ///
+    /// This will run both the build system generator command as well as the
+    /// command to build the library.
+    ///
+    /// Afterwards `cargo:rustc-link-search` is printed for the `lib` directory
+    /// of the install prefix, and for `lib64` if the project installed into it.
+    /// For Windows targets `bin` and the prefix itself are searched as well,
+    /// since import libraries are often installed next to their DLL. See
+    /// `header_only` for projects without libraries.
+    ///
+    /// The `CFLAGS` and `CXXFLAGS` environment variables are passed on along
+    /// with the flags of the compilers, before those given to `cflag` and
+    /// `cxxflag`. The `CMAKE_ARGS` environment variable, if set, is split
+    /// into arguments like a shell would, honouring single and double quotes
+    /// and backslash-escaped quotes and spaces, and these are appended to the
+    /// configure command.
+    pub fn build(&mut self) -> PathBuf {
+        match self.try_build() {
+            Ok(dst) => dst,
+            Err(e) => fail(&e.to_string()),
+        }
+    }
+
+    /// Runs `build` once for each of the given configurations (e.g. `Debug`
+    /// and `Release`), returning the install prefix of each.
+    ///
+    /// Every configuration gets its own build directory and install prefix,
+    /// `<out_dir>/<config>/build` and `<out_dir>/<config>`, so this also works
+    /// with single-config generators. `cargo:root` ends up pointing at the
+    /// last configuration.
+    pub fn build_configs(&mut self, configs: &[&str]) -> Vec<PathBuf> {
+        let base = self.dst_dir();
+        // Deferred values can only be computed once, so every configuration
+        // gets the same ones.
+        for (k, f) in std::mem::take(&mut self.lazy_defines) {
+            self.defines.push((k, f()));
+        }
+        // Each build starts from the settings as they were configured, rather
+        // than those the previous build derived from them.
+        let out_dir = self.out_dir.clone();
+        let profile = self.profile.clone();
+        let defines = self.defines.clone();
+        let copy_source = self.copy_source;
+        let mut prefixes = Vec::new();
+        for config in configs {
+            self.out_dir = Some(base.join(config));
+            self.profile(config);
+            let result = self.try_build();
+            self.out_dir = out_dir.clone();
+            self.profile = profile.clone();
+            self.defines = defines.clone();
+            self.copy_source = copy_source;
+            match result {
+                Ok(prefix) => prefixes.push(prefix),
+                Err(e) => fail(&e.to_string()),
+            }
+        }
+        prefixes
+    }
+
+    /// Like `build`, but returns a description of everything the build
+    /// produced rather than just the install prefix.
+    ///
+    /// ```no_run
+    /// let artifacts = cmake::Config::new("libfoo").build_artifacts();
+    /// artifacts.print_cargo_metadata();
+    /// println!("cargo:include={}", artifacts.include_dir.display());
+    /// ```
+    pub fn build_artifacts(&mut self) -> BuildArtifacts {
+        self.build();
+        let install_prefix = self.dst_dir();
+        let build_dir = self.build_dir();
+        let installed_files = read_install_manifest(&build_dir);
+        let lib_dirs = ["lib", "lib64"]
+            .iter()
+            .map(|dir| install_prefix.join(dir))
+            .filter(|dir| dir.is_dir())
+            .collect();
+        let libs = self.link_libs(&install_prefix);
+        BuildArtifacts {
+            include_dir: install_prefix.join("include"),
+            install_prefix,
+            build_dir,
+            installed_files,
+            lib_dirs,
+            libs,
+            metadata: self.metadata.lock().unwrap().clone(),
+        }
+    }
+
+    /// Like `build`, but also returns the libraries installed into the `lib`
+    /// and `lib64` directories of the install prefix, ready to be printed as
+    /// `cargo:rustc-link-lib`.
+    ///
+    /// Each library is named like `static=foo` or `dylib=foo`, classified by
+    /// its extension: `.a` files are static and `.so`, `.dylib` and `.dll.a`
+    /// files are shared. A `.lib` file counts as an import library if a DLL of
+    /// the same name was installed into `bin`, and as a static library
+    /// otherwise. See `list_static_libs` and `list_shared_libs`.
+    ///
+    /// ```no_run
+    /// let (dst, libs) = cmake::Config::new("libfoo").build_libs();
+    /// println!("cargo:rustc-link-search=native={}", dst.join("lib").display());
+    /// for lib in libs {
+    ///     println!("cargo:rustc-link-lib={}", lib);
+    /// }
+    /// ```
+    pub fn build_libs(&mut self) -> (PathBuf, Vec<String>) {
+        let dst = self.build();
+        let libs = self.link_libs(&dst);
+        (dst, libs)
+    }
+
+    /// Like `build`, but returns an error rather than panicking if a required
+    /// environment variable is missing, the build directory can't be
+    /// created, or cmake can't be run or one of its invocations fails.
+    ///
+    /// This allows a build script to recover, for example by falling back to
+    /// a prebuilt library. Problems found before cmake runs, such as a cmake
+    /// older than required or an unsupported target, are reported as
+    /// `Error::Config`.
+    pub fn try_build(&mut self) -> Result<PathBuf, Error> {
+        let result = self.run_steps(false);
+        self.dump_metadata();
+        result
+    }
+
+    /// Runs only the build system generation step, returning the build
+    /// directory.
+    ///
+    /// A failed configuration is reported as `Error::CommandFailed` with
+    /// `Step::Configure` and its cache is discarded, so the configuration can
+    /// be retried with different defines, e.g. with an optional feature
+    /// turned off, before calling `build`.
+    pub fn try_configure(&mut self) -> Result<PathBuf, Error> {
+        let result = self.run_steps(true);
+        self.dump_metadata();
+        result
+    }
+
+    fn run_steps(&mut self, configure_only: bool) -> Result<PathBuf, Error> {
+        self.check_env()?;
+        // Only built, see `use_existing_build_dir`.
+        let existing_build_dir = self.existing_build_dir.is_some();
+        // A `-G` among the extra arguments wins over an explicit generator,
+        // which wins over `CMAKE_GENERATOR` and in turn the platform defaults.
+        let extra_args = self.extra_configure_args();
+        let args_generator = generator_arg(&extra_args);
+        let mut generator = args_generator.clone().or_else(|| self.generator_name());
+        let start = Instant::now();
+        let quiet = self.verbosity == Verbosity::Quiet;
+        let capture = quiet || self.capture_output;
+        for (k, f) in std::mem::take(&mut self.lazy_defines) {
+            self.defines.push((k, f()));
+        }
+        if !existing_build_dir {
+            if !self.universal_archs.is_empty() {
+                self.check_universal_macos(generator.as_deref())?;
+                if !self.defined("CMAKE_OSX_ARCHITECTURES") {
+                    let archs = self.universal_archs.join(";");
+                    self.defines
+                        .push(("CMAKE_OSX_ARCHITECTURES".into(), archs.into()));
+                }
+            }
+            self.add_android_defines()?;
+            self.add_wasi_defines()?;
+        }
+        if let Some(var) = self.target_triple_var.clone() {
+            if !self.defined(&var) {
+                let triple = self.get_target_triple();
+                self.defines.push((var.into(), triple.into()));
+            }
+        }
+        self.dedup_defines();
+
+        let target_triple = self.get_target_triple();
+        let target = get_target(&target_triple);
+        let host = self.get_host_triple();
+        self.check_rosetta(&target_triple);
+        let msvc = target_triple.contains("msvc");
+        let (c_compiler, cxx_compiler) = self.compilers(&target_triple, &host);
+        let asm_compiler = c_compiler.clone();
+        self.add_musl_defines(&target_triple, &c_compiler);
+
+        let dst = self.dst_dir();
+        let build = self.build_dir();
+        if self.bootstrap.is_some() && !self.copy_source && !is_writable_dir(&self.path) {
+            self.copy_source = true;
+        }
+        if !existing_build_dir {
+            self.maybe_clear(&build);
+        }
+        // `out_dir` may point anywhere, so create the install prefix and any
+        // missing parents along with the build directory.
+        fs::create_dir_all(&build).map_err(|e| {
+            Error::Io(io::Error::new(
+                e.kind(),
+                format!("failed to create {}: {}", build.display(), e),
+            ))
+        })?;
+
+        if self.rerun_if_changed {
+            self.print_env_reruns();
+            self.print_rerun_if_changed(&dst)?;
+        }
+        // Read by cmake itself when no generator is passed.
+        self.getenv_os("CMAKE_GENERATOR");
+
+        let executable = self.cmake_executable();
+        let profile = self
+            .profile
+            .clone()
+            .unwrap_or_else(|| self.default_profile());
+        // An existing build directory is trusted as-is, so nothing is written
+        // to it or checked before building it.
+        let (toolchain_defines, file_api, configure_time, _staged_presets) = if existing_build_dir {
+            if !quiet {
+                println!("Using the existing build directory. Skipping configuration step.");
+            }
+            if let Some(cached) = cache_value(&build, "CMAKE_GENERATOR") {
+                generator = Some(cached.into());
+            }
+            (
+                Vec::new(),
+                self.build_all_targets,
+                Duration::default(),
+                None,
+            )
+        } else {
+            if self.copy_source {
+                let source = self.source_dir();
+                sync_tree(&self.path, &source, &dst).map_err(|e| {
+                    Error::Io(io::Error::new(
+                        e.kind(),
+                        format!(
+                            "failed to copy {} to {}: {}",
+                            self.path.display(),
+                            source.display(),
+                            e
+                        ),
+                    ))
+                })?;
+            }
+
+            if let Some(mut bootstrap) = self.bootstrap.take() {
+                let source = self.source_dir();
+                let inputs = Some(bootstrap.get_program())
+                    .into_iter()
+                    .chain(bootstrap.get_args())
+                    .map(|arg| self.path.join(arg))
+                    .filter(|path| path.starts_with(&self.path) && path.is_file())
+                    .collect::<Vec<_>>();
+                // Already covered when the whole source tree is registered.
+                if !self.rerun_if_changed {
+                    for input in inputs {
+                        self.print(&format!("cargo:rerun-if-changed={}", input.display()));
+                    }
+                }
+                if bootstrap.get_current_dir().is_none() {
+                    bootstrap.current_dir(&source);
+                }
+                let program = bootstrap.get_program().to_string_lossy().into_owned();
+                let result = run(
+                    &mut bootstrap,
+                    &program,
+                    Step::Configure,
+                    quiet,
+                    capture,
+                    None,
+                );
+                self.bootstrap = Some(bootstrap);
+                result?;
+            }
+
+            // Removed again when dropped at the end of the build.
+            let staged_presets = match self.presets_file {
+                Some(ref presets) => Some(StagedFile::presets(&self.source_dir(), presets)?),
+                None => None,
+            };
+
+            // Add all our dependencies to our cmake paths
+            let mut cmake_prefix_path = Vec::new();
+            for dep in &self.deps {
+                let dep = dep.to_uppercase().replace('-', "_");
+                if let Some(root) = self.getenv_os(&format!("DEP_{}_ROOT", dep)) {
+                    cmake_prefix_path.push(PathBuf::from(root));
+                }
+            }
+            for file in &self.prefix_path_files {
+                self.print(&format!("cargo:rerun-if-changed={}", file.display()));
+                cmake_prefix_path.extend(read_prefix_path_file(file)?);
+            }
+            let system_prefix = self.getenv_os("CMAKE_PREFIX_PATH").unwrap_or_default();
+            cmake_prefix_path.extend(env::split_paths(&system_prefix).map(|s| s.to_owned()));
+            let cmake_prefix_path = env::join_paths(&cmake_prefix_path).unwrap();
+
+            let mut pkg_config_path = Vec::new();
+            for dep in &self.pkgconfig_deps {
+                let dep = dep.to_uppercase().replace('-', "_");
+                if let Some(root) = self.getenv_os(&format!("DEP_{}_ROOT", dep)) {
+                    for dir in &["lib/pkgconfig", "lib64/pkgconfig"] {
+                        let dir = Path::new(&root).join(dir);
+                        if dir.is_dir() {
+                            pkg_config_path.push(dir);
+                        }
+                    }
+                }
+            }
+            let pkg_config_path = if pkg_config_path.is_empty() {
+                None
+            } else {
+                let system = self.getenv_os("PKG_CONFIG_PATH").unwrap_or_default();
+                pkg_config_path.extend(env::split_paths(&system));
+                Some(env::join_paths(&pkg_config_path).unwrap())
+            };
+
+            // Build up the first cmake command to build the build system.
+            if let Some((major, minor)) = self.required_cmake_version {
+                match executable_version(&executable) {
+                    Some((found_major, found_minor, _))
+                        if (found_major, found_minor) >= (major, minor) => {}
+                    Some((found_major, found_minor, found_patch)) => {
+                        return Err(Error::Config(format!(
+                            "cmake {}.{} required, found {}.{}.{}",
+                            major, minor, found_major, found_minor, found_patch
+                        )))
+                    }
+                    None => {
+                        return Err(Error::Config(format!(
+                            "cmake {}.{} required, but the version of `{}` could not be determined",
+                            major,
+                            minor,
+                            executable.to_string_lossy()
+                        )))
+                    }
+                }
+            }
+            // A cache left over from an in-source build makes cmake treat the source
+            // tree as a build tree, which fails in confusing ways.
+            let stray_cache = self.path.join("CMakeCache.txt");
+            if stray_cache.exists() {
+                return Err(Error::Config(format!(
+                    "found {} from an earlier in-source cmake build; remove it along \
+                 with the `CMakeFiles` directory next to it to build out of tree",
+                    stray_cache.display()
+                )));
+            }
+            // Catch a project needing a newer cmake before configuring, where it
+            // would only fail midway through.
+            if let Some(required) = project_min_cmake_version(&self.source_dir()) {
+                if let Some(found) = executable_version(&executable) {
+                    if found < required {
+                        return Err(Error::Config(format!(
+                            "{} requires cmake {}.{}.{} (`cmake_minimum_required`), found {}.{}.{}",
+                            self.source_dir().join("CMakeLists.txt").display(),
+                            required.0,
+                            required.1,
+                            required.2,
+                            found.0,
+                            found.1,
+                            found.2
+                        )));
+                    }
+                }
+            }
+            if self.preset.is_some()
+                && !matches!(executable_version(&executable), Some(v) if v >= (3, 19, 0))
+            {
+                return Err(Error::Config(
+                    "`preset` requires cmake 3.19 or later".to_string(),
+                ));
+            }
+            let mut cmd = Command::new(&executable);
+            self.clear_env(&mut cmd);
+
+            if self.verbose_cmake {
+                cmd.arg("-Wdev");
+                cmd.arg("--debug-output");
+            }
+
+            // Newer versions take the source, build and install directories as
+            // explicit options instead of relying on the working directory and a
+            // cache variable.
+            let explicit_dirs =
+                matches!(executable_version(&executable), Some(v) if v >= (3, 21, 0));
+            if explicit_dirs {
+                cmd.arg("-S").arg(self.source_dir()).arg("-B").arg(&build);
+            } else {
+                cmd.arg(self.source_dir());
+            }
+            cmd.current_dir(&build);
+            for k in &self.undefines {
+                let mut arg = OsString::from("-U");
+                arg.push(k);
+                cmd.arg(arg);
+            }
+            if self.no_warning_as_error {
+                match executable_version(&executable) {
+                Some(version) if version >= (3, 24, 0) => {
+                    cmd.arg("--compile-no-warning-as-error");
+                }
+                _ => self.print(
+                    "cargo:warning=`no_warning_as_error` requires cmake 3.24 or later; ignoring it",
+                ),
+            }
+            }
+            if let Some(ref graphviz) = self.graphviz {
+                if let Some(parent) = graphviz.parent() {
+                    let _ = fs::create_dir_all(parent);
+                }
+                let mut arg = OsString::from("--graphviz=");
+                arg.push(graphviz);
+                cmd.arg(arg);
+            }
+            if msvc && generator.is_none() && self.msvc_prefer_makefiles {
+                if find_on_path("ninja") {
+                    generator = Some("Ninja".into());
+                } else if find_on_path("jom") {
+                    generator = Some("NMake Makefiles JOM".into());
+                }
+            }
+            if target_triple.contains("windows-gnu") {
+                // On MinGW we need to coerce cmake to not generate a visual
+                // studio build system but instead use makefiles that MinGW can
+                // use to build.
+                if host.contains("windows") && generator.is_none() {
+                    // If make.exe isn't found, that means we may be using a MinGW
+                    // toolchain instead of a MSYS2 toolchain. If neither is found,
+                    // the build cannot continue.
+                    let has_msys2 = Command::new("make")
+                        .arg("--version")
+                        .output()
+                        .err()
+                        .map(|e| e.kind() != ErrorKind::NotFound)
+                        .unwrap_or(true);
+                    let has_mingw32 = Command::new("mingw32-make")
+                        .arg("--version")
+                        .output()
+                        .err()
+                        .map(|e| e.kind() != ErrorKind::NotFound)
+                        .unwrap_or(true);
+
+                    let generator = match (has_msys2, has_mingw32) {
+                    (true, _) => "MSYS Makefiles",
+                    (false, true) => "MinGW Makefiles",
+                    (false, false) => {
+                        return Err(Error::Config(
+                            "no valid generator found for GNU toolchain; MSYS or MinGW must be installed"
+                                .to_string(),
+                        ))
+                    }
+                };
+
+                    cmd.arg("-G").arg(generator);
+                }
+            } else if msvc {
+                // If we're on MSVC we need to be sure to use the right generator or
+                // otherwise we won't get 32/64 bit correct automatically.
+                // This also guarantees that NMake generator isn't chosen implicitly.
+                // Only the Visual Studio generators take the architecture through
+                // `-A`/`-T`; makefile and Ninja generators get it from the compiler
+                // environment instead.
+                let using_vs_generator = match generator {
+                    Some(ref generator) => generator.to_string_lossy().starts_with("Visual Studio"),
+                    None => {
+                        cmd.arg("-G")
+                            .arg(self.visual_studio_generator(&target_triple));
+                        true
+                    }
+                };
+                if using_vs_generator {
+                    // The platform (`-A`) follows the target, while the toolset
+                    // (`-Thost=`) follows the machine we're running on, so e.g. a
+                    // 32-bit target built on a 64-bit host still uses the 64-bit
+                    // hosted compiler and isn't limited by its address space.
+                    let platform = match msvc_platform(&target_triple) {
+                        Some(platform) => platform,
+                        None => panic!("unsupported msvc target: {}", target_triple),
+                    };
+                    let host_arch = host.split('-').next().unwrap_or(env::consts::ARCH);
+                    let toolset = match host_arch {
+                        "x86_64" => Some("x64"),
+                        "aarch64" => Some("ARM64"),
+                        "i686" | "i586" | "x86" => Some("x86"),
+                        _ => None,
+                    };
+                    if let Some(toolset) = toolset {
+                        cmd.arg(format!("-Thost={}", toolset));
+                    }
+                    cmd.arg(format!("-A{}", platform));
+                }
+            }
+
+            let toolchain_defines = self.toolchain_defines(
+                &target_triple,
+                &host,
+                &c_compiler,
+                &cxx_compiler,
+                generator.as_deref(),
+            );
+            if self.uses_generated_toolchain_file(&target_triple, &host) {
+                let toolchain_file =
+                    write_toolchain_file(&build, &target_triple, &toolchain_defines)?;
+                let mut arg = OsString::from("-DCMAKE_TOOLCHAIN_FILE=");
+                arg.push(cmake_path(toolchain_file.as_os_str()));
+                cmd.arg(arg);
+            } else {
+                for (k, v) in &toolchain_defines {
+                    cmd.arg(define_arg(k, v));
+                }
+            }
+
+            if let (Some(ref generator), None) = (&generator, &args_generator) {
+                cmd.arg("-G").arg(generator);
+            }
+            for (k, v) in self.ordered_defines() {
+                let mut os = OsString::from("-D");
+                os.push(k);
+                os.push("=");
+                // cmake treats `\` as an escape in some places, so paths are
+                // passed with forward slashes.
+                let ty = k.to_string_lossy();
+                if ty.ends_with(":PATH") || ty.ends_with(":FILEPATH") {
+                    os.push(cmake_path(v));
+                } else {
+                    os.push(v);
+                }
+                cmd.arg(os);
+            }
+
+            if let Some(ref perms) = self.install_default_permissions {
+                if !self.defined("CMAKE_INSTALL_DEFAULT_DIRECTORY_PERMISSIONS") {
+                    cmd.arg(format!(
+                        "-DCMAKE_INSTALL_DEFAULT_DIRECTORY_PERMISSIONS={}",
+                        perms
+                    ));
+                }
+            }
+
+            if let Some(ref prefix) = self.external_project_prefix {
+                fs::create_dir_all(prefix).map_err(|e| io_context("create", prefix, e))?;
+                if !self.defined("EP_PREFIX") {
+                    let mut arg = OsString::from("-DEP_PREFIX=");
+                    arg.push(prefix);
+                    cmd.arg(arg);
+                }
+                if !self.defined("CMAKE_PROJECT_INCLUDE_BEFORE") {
+                    let include = build.join("cmake-rs-ep-prefix.cmake");
+                    write_if_changed(
+                        &include,
+                        &format!(
+                            "# Generated by the cmake crate.\n\
+                         set_directory_properties(PROPERTIES EP_PREFIX \"{}\")\n",
+                            cmake_quote(&prefix.to_string_lossy())
+                        ),
+                    )?;
+                    let mut arg = OsString::from("-DCMAKE_PROJECT_INCLUDE_BEFORE=");
+                    arg.push(&include);
+                    cmd.arg(arg);
+                }
+            }
+
+            if !self.defined("CMAKE_INSTALL_PREFIX") {
+                if explicit_dirs {
+                    cmd.arg("--install-prefix").arg(cmake_path(dst.as_os_str()));
+                } else {
+                    let mut dstflag = OsString::from("-DCMAKE_INSTALL_PREFIX=");
+                    dstflag.push(cmake_path(dst.as_os_str()));
+                    cmd.arg(dstflag);
+                }
+            }
+
+            let build_type = self
+                .defines
+                .iter()
+                .find(|(a, _)| define_name(a) == "CMAKE_BUILD_TYPE")
+                .map(|x| x.1.to_str().unwrap())
+                .unwrap_or(&profile);
+            let build_type_upcase = build_type
+                .chars()
+                .flat_map(|c| c.to_uppercase())
+                .collect::<String>();
+
+            {
+                // let cmake deal with optimization/debuginfo
+                let skip_arg = |arg: &OsStr| match arg.to_str() {
+                    Some(s) => s.starts_with("-O") || s.starts_with("/O") || s == "-g",
+                    None => false,
+                };
+                let cc_flag_kinds = if self.universal_archs.is_empty() {
+                    self.cc_flag_kinds
+                } else {
+                    CcFlagKinds(self.cc_flag_kinds.0 & !CcFlagKinds::TARGET.0)
+                };
+                let mut set_compiler = |kind: &str, compiler: &cc::Tool, extra: &OsString| {
+                    let mut add_compiler_flags = |flag_var_name: &str| {
+                        if !self.defined(flag_var_name) {
+                            let mut compiler_flags = OsString::new();
+                            let mut args = compiler.args().iter();
+                            while let Some(arg) = args.next() {
+                                if skip_arg(arg) {
+                                    continue;
+                                }
+                                let (kind, takes_value) = cc_flag_kind(arg);
+                                let value = if takes_value { args.next() } else { None };
+                                if !cc_flag_kinds.contains(kind) {
+                                    continue;
+                                }
+                                compiler_flags.push(" ");
+                                compiler_flags.push(arg);
+                                if let Some(value) = value {
+                                    compiler_flags.push(" ");
+                                    compiler_flags.push(value);
+                                }
+                            }
+                            target.filter_compiler_args(&mut compiler_flags);
+
+                            // We want to filter compiler args from cc-rs, but not user-supplied ones,
+                            // so we add user-supplied ones after we filter.
+                            compiler_flags.push(extra);
+
+                            let mut flagsflag = OsString::from("-D");
+                            flagsflag.push(flag_var_name);
+                            flagsflag.push("=");
+                            flagsflag.push(compiler_flags);
+
+                            cmd.arg(flagsflag);
+                        }
+                    };
+
+                    let flag_var = format!("CMAKE_{}_FLAGS", kind);
+                    add_compiler_flags(&flag_var);
+
+                    // The visual studio generator apparently doesn't respect
+                    // `CMAKE_C_FLAGS` but does respect `CMAKE_C_FLAGS_RELEASE` and
+                    // such. We need to communicate /MD vs /MT, so set those vars
+                    // here.
+                    //
+                    // Note that for other generators, though, this *overrides*
+                    // things like the optimization flags, which is bad.
+                    if generator.is_none() && msvc {
+                        let flag_var_alt = format!("CMAKE_{}_FLAGS_{}", kind, build_type_upcase);
+                        add_compiler_flags(&flag_var_alt);
+                    }
+                };
+
+                let mut cflags = self.cflags.clone();
+                let mut cxxflags = self.cxxflags.clone();
+                if self.warnings_as_errors
+                    && !matches!(executable_version(&executable), Some(v) if v >= (3, 24, 0))
+                {
+                    cflags.push(if c_compiler.is_like_msvc() {
+                        " /WX"
+                    } else {
+                        " -Werror"
+                    });
+                    cxxflags.push(if cxx_compiler.is_like_msvc() {
+                        " /WX"
+                    } else {
+                        " -Werror"
+                    });
+                }
+                set_compiler("C", &c_compiler, &cflags);
+                set_compiler("CXX", &cxx_compiler, &cxxflags);
+                set_compiler("ASM", &asm_compiler, &self.asmflags);
+            }
+
+            for &(kind, config_flags) in
+                &[("C", &self.config_cflags), ("CXX", &self.config_cxxflags)]
+            {
+                for (config, flags) in config_flags.iter() {
+                    let flag_var = format!("CMAKE_{}_FLAGS_{}_INIT", kind, config);
+                    if !self.defined(&flag_var) {
+                        let mut flagsflag = OsString::from("-D");
+                        flagsflag.push(&flag_var);
+                        flagsflag.push("=");
+                        flagsflag.push(flags);
+                        cmd.arg(flagsflag);
+                    }
+                }
+            }
+
+            if !self.defined("CMAKE_BUILD_TYPE") {
+                cmd.arg(format!("-DCMAKE_BUILD_TYPE={}", profile));
+            }
+
+            // An explicit CRT choice is also passed to cmake's own CRT selection,
+            // with the debug CRT for `Debug` builds.
+            if let Some(static_crt) = self.static_crt {
+                if msvc
+                    && !self.defined("CMAKE_MSVC_RUNTIME_LIBRARY")
+                    && matches!(executable_version(&executable), Some(v) if v >= (3, 15, 0))
+                {
+                    cmd.arg(format!(
+                        "-DCMAKE_MSVC_RUNTIME_LIBRARY=MultiThreaded{}{}",
+                        if profile == "Debug" { "Debug" } else { "" },
+                        if static_crt { "" } else { "DLL" }
+                    ));
+                    if !self.defined("CMAKE_POLICY_DEFAULT_CMP0091") {
+                        cmd.arg("-DCMAKE_POLICY_DEFAULT_CMP0091=NEW");
+                    }
+                }
+            }
+
+            if self.verbose_make {
+                cmd.arg("-DCMAKE_VERBOSE_MAKEFILE:BOOL=ON");
+            }
+
+            if self.warnings_as_errors
+                && !self.defined("CMAKE_COMPILE_WARNING_AS_ERROR")
+                && matches!(executable_version(&executable), Some(v) if v >= (3, 24, 0))
+            {
+                cmd.arg("-DCMAKE_COMPILE_WARNING_AS_ERROR:BOOL=ON");
+            }
+
+            let linker = self.linker.clone().or_else(|| {
+                if !self.rust_linker {
+                    return None;
+                }
+                let var = format!(
+                    "CARGO_TARGET_{}_LINKER",
+                    target_triple.to_uppercase().replace(['-', '.'], "_")
+                );
+                self.getenv_os("RUSTC_LINKER")
+                    .or_else(|| self.getenv_os(&var))
+                    .map(PathBuf::from)
+            });
+            if let Some(linker) = linker {
+                for (var, value) in linker_defines(&linker, msvc) {
+                    if !self.defined(&var) && !self.defined(var.trim_end_matches("_INIT")) {
+                        cmd.arg(define_arg(&var, &value));
+                    }
+                }
+            }
+
+            let static_musl = self.links_musl_statically(&target_triple);
+            for kind in &["EXE", "SHARED", "MODULE"] {
+                let mut flags = self.ldflags.clone();
+                if static_musl && *kind == "EXE" {
+                    flags.push(" -static");
+                }
+                let var = format!("CMAKE_{}_LINKER_FLAGS", kind);
+                if !flags.is_empty() && !self.defined(&var) {
+                    cmd.arg(define_arg(&var, &flags));
+                }
+            }
+
+            if self.relocatable_install && !target_triple.contains("windows") {
+                let apple = target_triple.contains("apple");
+                let origin = if apple { "@loader_path" } else { "$ORIGIN" };
+                let mut defines = vec![
+                    ("CMAKE_INSTALL_RPATH", format!("{}/../lib", origin)),
+                    ("CMAKE_INSTALL_RPATH_USE_LINK_PATH", "OFF".to_string()),
+                ];
+                if apple {
+                    defines.push(("CMAKE_MACOSX_RPATH", "ON".to_string()));
+                    defines.push(("CMAKE_INSTALL_NAME_DIR", "@rpath".to_string()));
+                }
+                for (var, value) in defines {
+                    if !self.defined(var) {
+                        cmd.arg(format!("-D{}={}", var, value));
+                    }
+                }
+            }
+
+            if let Some(pch) = self.pch {
+                if !self.defined("CMAKE_DISABLE_PRECOMPILE_HEADERS") {
+                    let disable = if pch { "OFF" } else { "ON" };
+                    cmd.arg(format!("-DCMAKE_DISABLE_PRECOMPILE_HEADERS={}", disable));
+                }
+            }
+
+            if let Some(pic) = self.pic {
+                if !self.defined("CMAKE_POSITION_INDEPENDENT_CODE") {
+                    let value = if pic { "ON" } else { "OFF" };
+                    cmd.arg(format!("-DCMAKE_POSITION_INDEPENDENT_CODE={}", value));
+                }
+            }
+
+            let launcher = self
+                .compiler_launcher
+                .clone()
+                .or_else(|| self.getenv_os("CMAKE_COMPILER_LAUNCHER"))
+                .or_else(|| {
+                    self.getenv_os("RUSTC_WRAPPER").filter(|wrapper| {
+                        let stem = Path::new(wrapper).file_stem();
+                        stem == Some(OsStr::new("ccache")) || stem == Some(OsStr::new("sccache"))
+                    })
+                });
+            if let Some(launcher) = launcher.filter(|l| !l.is_empty()) {
+                for kind in &["C", "CXX"] {
+                    let var = format!("CMAKE_{}_COMPILER_LAUNCHER", kind);
+                    if !self.defined(&var) {
+                        cmd.arg(define_arg(&var, &launcher));
+                    }
+                }
+            }
+
+            if self.hidden_visibility {
+                for &(var, value) in &[
+                    ("CMAKE_C_VISIBILITY_PRESET", "hidden"),
+                    ("CMAKE_CXX_VISIBILITY_PRESET", "hidden"),
+                    ("CMAKE_VISIBILITY_INLINES_HIDDEN", "ON"),
+                ] {
+                    if !self.defined(var) {
+                        cmd.arg(format!("-D{}={}", var, value));
+                    }
+                }
+            }
+
+            if self.skip_install_all_dependency
+                && !self.defined("CMAKE_SKIP_INSTALL_ALL_DEPENDENCY")
+            {
+                cmd.arg("-DCMAKE_SKIP_INSTALL_ALL_DEPENDENCY:BOOL=ON");
+            }
+
+            if self.find_debug && !self.defined("CMAKE_FIND_DEBUG_MODE") {
+                match executable_version(&executable) {
+                    Some(version) if version >= (3, 17, 0) => {
+                        cmd.arg("-DCMAKE_FIND_DEBUG_MODE:BOOL=ON");
+                    }
+                    _ => self.print(
+                        "cargo:warning=`find_debug` requires cmake 3.17 or later; ignoring it",
+                    ),
+                }
+            }
+
+            if self.message_context && !self.defined("CMAKE_MESSAGE_CONTEXT_SHOW") {
+                match executable_version(&executable) {
+                    Some(version) if version >= (3, 17, 0) => {
+                        cmd.arg("-DCMAKE_MESSAGE_CONTEXT_SHOW:BOOL=ON");
+                    }
+                    _ => self.print(
+                        "cargo:warning=`message_context` requires cmake 3.17 or later; ignoring it",
+                    ),
+                }
+            }
+
+            if self.export_compile_commands && !self.defined("CMAKE_EXPORT_COMPILE_COMMANDS") {
+                cmd.arg("-DCMAKE_EXPORT_COMPILE_COMMANDS:BOOL=ON");
+            }
+
+            if !self.defined("CMAKE_TOOLCHAIN_FILE") {
+                if let Some(s) = self.getenv("CMAKE_TOOLCHAIN_FILE") {
+                    cmd.arg(format!("-DCMAKE_TOOLCHAIN_FILE={}", s));
+                }
+            }
+
+            for (k, v) in c_compiler
+                .get_envs()
+                .chain(self.env.iter().map(|(k, v)| (&**k, &**v)))
+            {
+                if target.should_exclude_env_var(k, v) {
+                    continue;
+                }
+                cmd.env(k, v);
+            }
+
+            if let Some(ref preset) = self.preset {
+                // The preset decides on the generator, the toolchain and the cache
+                // variables, so only the directories are passed along with it.
+                let mut preset_cmd = self.command_like(&cmd);
+                for k in &self.undefines {
+                    let mut arg = OsString::from("-U");
+                    arg.push(k);
+                    preset_cmd.arg(arg);
+                }
+                preset_cmd
+                    .arg("--preset")
+                    .arg(preset)
+                    .arg("-S")
+                    .arg(self.source_dir())
+                    .arg("-B")
+                    .arg(&build)
+                    .arg(define_arg(
+                        "CMAKE_INSTALL_PREFIX",
+                        &cmake_path(dst.as_os_str()),
+                    ))
+                    .current_dir(&build);
+                cmd = preset_cmd;
+            }
+
+            let (defines, args): (Vec<_>, Vec<_>) = cmd
+                .get_args()
+                .map(|arg| arg.to_owned())
+                .partition(|arg| arg.to_string_lossy().starts_with("-D"));
+            let mut initial_cache = String::new();
+            if self
+                .defines_via_cache_file
+                .unwrap_or(cfg!(windows) && exceeds_define_limits(&defines))
+            {
+                for define in &defines {
+                    let define = define.to_string_lossy();
+                    let (key, value) = define[2..].split_once('=').unwrap_or((&define[2..], ""));
+                    let (name, ty) = key.split_once(':').unwrap_or((key, "STRING"));
+                    initial_cache.push_str(&format!(
+                        "set({} \"{}\" CACHE {} \"\" FORCE)\n",
+                        name,
+                        cmake_quote(value),
+                        ty
+                    ));
+                }
+                let mut short_cmd = self.command_like(&cmd);
+                short_cmd.args(&args);
+                if let Some(dir) = cmd.get_current_dir() {
+                    short_cmd.current_dir(dir);
+                }
+                cmd = short_cmd;
+            }
+
+            cmd.args(&extra_args);
+
+            // Settings that can't be passed as `-D` go through an initial cache
+            // script, which is only rewritten when it changes.
+            initial_cache.push_str(&self.initial_cache);
+            for (k, v) in &self.forced_defines {
+                let key = k.to_string_lossy();
+                let (name, ty) = key.split_once(':').unwrap_or((&key, "STRING"));
+                initial_cache.push_str(&format!(
+                    "set({} \"{}\" CACHE {} \"\" FORCE)\n",
+                    name,
+                    cmake_quote(&v.to_string_lossy()),
+                    ty
+                ));
+            }
+            let script = build.join("cmake-rs-initial-cache.cmake");
+            if !initial_cache.is_empty() {
+                write_if_changed(&script, &initial_cache)?;
+                cmd.arg("-C").arg(&script);
+            } else if script.exists() {
+                let _ = fs::remove_file(&script);
+            }
+
+            // Ask cmake to describe the project's targets while configuring.
+            let file_api = self.build_all_targets
+                && matches!(executable_version(&executable), Some(v) if v >= (3, 15, 0));
+            if file_api {
+                let query = build.join(".cmake/api/v1/query");
+                fs::create_dir_all(&query)
+                    .and_then(|_| File::create(query.join("codemodel-v2")))
+                    .map_err(|e| {
+                        Error::Io(io::Error::new(
+                            e.kind(),
+                            format!("failed to create {}: {}", query.display(), e),
+                        ))
+                    })?;
+            } else if self.build_all_targets {
+                self.print(
+                "cargo:warning=`build_all_including_excluded` requires cmake 3.15 or later; ignoring it",
+            );
+            }
+
+            self.check_build_tool(generator.as_deref(), &target_triple)?;
+            let configure_start = Instant::now();
+            cmd.env("CMAKE_PREFIX_PATH", cmake_prefix_path);
+            if let Some(path) = pkg_config_path {
+                cmd.env("PKG_CONFIG_PATH", path);
+            }
+            // Without `always_configure` an existing cache is only reused if it was
+            // configured with the same command.
+            let config_hash = command_hash(&cmd, &initial_cache);
+            let hash_file = build.join(".cmake-rs-config-hash");
+            let up_to_date = build.join("CMakeCache.txt").exists()
+                && fs::read_to_string(&hash_file).ok().as_deref() == Some(&*config_hash);
+            if self.always_configure || !up_to_date {
+                let surface = self.surface_cmake_warnings;
+                let result = run(
+                    &mut cmd,
+                    "cmake",
+                    Step::Configure,
+                    quiet,
+                    capture || surface,
+                    self.configure_timeout,
+                );
+                if result.is_err() {
+                    let _ = fs::remove_file(build.join("CMakeCache.txt"));
+                    let _ = fs::remove_file(&hash_file);
+                }
+                let (stdout, stderr) = result?;
+                if surface {
+                    if !capture {
+                        let _ = io::stdout().write_all(&stdout);
+                        let _ = io::stderr().write_all(&stderr);
+                    }
+                    for warning in cmake_warnings(&String::from_utf8_lossy(&stderr)) {
+                        self.print(&format!("cargo:warning={}", warning));
+                    }
+                }
+                let _ = fs::write(&hash_file, &config_hash);
+            } else if !quiet {
+                println!("CMake project was already configured. Skipping configuration step.");
+            }
+            (
+                toolchain_defines,
+                file_api,
+                configure_start.elapsed(),
+                staged_presets,
+            )
+        };
+        if configure_only {
+            return Ok(build);
+        }
+
+        let jobs = self
+            .parallel
+            .map(|jobs| jobs.to_string())
+            .or_else(|| self.getenv("NUM_JOBS"))
+            .unwrap_or_else(|| {
+                thread::available_parallelism()
+                    .map_or(1, |n| n.get())
+                    .to_string()
+            });
+        // `--parallel` lets cmake pick the right flag for the native tool, older
+        // versions get the tool's own flag after the `--` separator.
+        let native_parallel = matches!(executable_version(&executable), Some(v) if v >= (3, 12, 0));
+        let mut parallel = None;
+        let mut makeflags = None;
+        let mut parallel_flags = None;
+
+        match generator.as_ref().map(|g| g.to_string_lossy()) {
+            Some(ref g) if g.contains("NMake") => {
+                // NMake creates `Makefile`s, but doesn't understand `-jN`.
+            }
+            Some(ref g) if g.contains("Ninja") && !native_parallel => {
+                parallel_flags = Some(format!("-j{}", jobs));
+            }
+            Some(ref g) if g.contains("Visual Studio") && !native_parallel => {
+                parallel_flags = Some(format!("/m:{}", jobs));
+            }
+            _ if fs::metadata(build.join("Makefile")).is_ok() => {
+                match self.getenv_os("CARGO_MAKEFLAGS") {
+                    // Only do this on non-windows and non-bsd
+                    // On Windows, we could be invoking make instead of
+                    // mingw32-make which doesn't work with our jobserver
+                    // bsdmake also does not work with our job server
+                    Some(ref s)
+                        if self.parallel.is_none()
+                            && !(cfg!(windows)
+                                || cfg!(target_os = "openbsd")
+                                || cfg!(target_os = "netbsd")
+                                || cfg!(target_os = "freebsd")
+                                || cfg!(target_os = "dragonfly")) =>
+                    {
+                        makeflags = Some(s.clone())
+                    }
+
+                    _ if native_parallel => parallel = Some(jobs),
+                    // This looks like `make`, let's hope it understands `-jN`.
+                    _ => makeflags = Some(OsString::from(format!("-j{}", jobs))),
+                }
+            }
+            _ if native_parallel => parallel = Some(jobs),
+            _ => {}
+        }
+
+        // And build!
+        let mut cmake_targets = if !self.build_target_sequence.is_empty() {
+            self.build_target_sequence
+                .iter()
+                .map(|t| vec![t.clone()])
+                .collect()
+        } else if self.no_build_target {
+            vec![vec![]]
+        } else {
+            vec![vec![self
+                .cmake_target
+                .clone()
+                .unwrap_or("install".to_string())]]
+        };
+        if self.build_all_targets && file_api {
+            let all = file_api_targets(&build);
+            if !all.is_empty() {
+                cmake_targets.insert(0, all);
+            }
+        }
+        let installs = cmake_targets.iter().any(|t| *t == ["install"]);
+        if installs {
+            check_install_dir(&dst)?;
+        }
+
+        // Newer versions install in a separate `cmake --install` step, which
+        // also works for multi-config generators.
+        let separate_install =
+            matches!(executable_version(&executable), Some(v) if v >= (3, 15, 0));
+        let command = || {
+            let mut cmd = Command::new(&executable);
+            self.clear_env(&mut cmd);
+            for (k, v) in c_compiler
+                .get_envs()
+                .chain(self.env.iter().map(|(k, v)| (&**k, &**v)))
+            {
+                if target.should_exclude_env_var(k, v) {
+                    continue;
+                }
+                cmd.env(k, v);
+            }
+            cmd
+        };
+
+        let previous_manifest = if self.prune_stale_installs && installs {
+            read_install_manifest(&build)
+        } else {
+            Vec::new()
+        };
+
+        let generator_kind = generator_kind(generator.as_deref(), &target_triple);
+        if self.max_load.is_some() && generator_kind != Some(GeneratorKind::Make) {
+            self.print("cargo:warning=`max_load` only applies to Makefile generators; ignoring it");
+        }
+        // A build preset of the same name supplies the build options, as long
+        // as it builds the directory configured above: cmake builds the
+        // `binaryDir` of its configure preset when one is declared.
+        let build_preset = match self.preset {
+            Some(ref name) => match find_build_preset(&self.source_dir(), name) {
+                Some(ref preset) if preset.binary_dir => {
+                    self.print(&format!(
+                        "cargo:warning=not using build preset `{}` as its configure preset \
+                         declares a `binaryDir`",
+                        name
+                    ));
+                    None
+                }
+                preset => preset,
+            },
+            None => None,
+        };
+        let build_start = Instant::now();
+        for cmake_target in cmake_targets {
+            let install = separate_install && cmake_target == ["install"];
+            let cmake_target = if install { Vec::new() } else { cmake_target };
+            let mut cmd = command();
+
+            if let Some(ref flags) = makeflags {
+                cmd.env("MAKEFLAGS", flags);
+            }
+
+            match (&self.preset, &build_preset) {
+                // cmake looks for the presets in the working directory.
+                (Some(name), Some(_)) => {
+                    cmd.arg("--build")
+                        .arg(&build)
+                        .arg("--preset")
+                        .arg(name)
+                        .current_dir(self.source_dir());
+                }
+                _ => {
+                    cmd.arg("--build").arg(".").current_dir(&build);
+                }
+            }
+
+            if !cmake_target.is_empty() {
+                cmd.arg("--target").args(&cmake_target);
+            }
+            if self.clean_first {
+                cmd.arg("--clean-first");
+            }
+
+            // Older versions don't know `--verbose`, which Makefiles can make
+            // up for through `VERBOSE=1` after the `--` separator.
+            let verbose = self.verbose_build || self.verbose_make;
+            let native_verbose =
+                matches!(executable_version(&executable), Some(v) if v >= (3, 14, 0));
+            if verbose && native_verbose {
+                cmd.arg("--verbose");
+            }
+            if let Some(ref jobs) = parallel {
+                cmd.arg("--parallel").arg(jobs);
+            }
+            // The preset selects the configuration.
+            if self.preset.is_none() {
+                cmd.arg("--config").arg(&profile);
+            }
+            cmd.arg("--").args(&self.build_args);
+            for (_, arg) in self
+                .native_tool_args
+                .iter()
+                .filter(|(kind, _)| Some(*kind) == generator_kind)
+            {
+                cmd.arg(arg);
+            }
+
+            if let Some(ref flags) = parallel_flags {
+                cmd.arg(flags);
+            }
+            if verbose && !native_verbose && generator_kind == Some(GeneratorKind::Make) {
+                cmd.arg("VERBOSE=1");
+            }
+            if let Some(load) = self.max_load {
+                if generator_kind == Some(GeneratorKind::Make) {
+                    cmd.arg("-l").arg(load.to_string());
+                }
+            }
+
+            run(
+                &mut cmd,
+                "cmake",
+                Step::Build,
+                quiet,
+                capture,
+                self.build_timeout,
+            )?;
+
+            if install {
+                let mut cmd = command();
+                cmd.arg("--install").arg(".");
+                if self.preset.is_none() {
+                    cmd.arg("--config").arg(&profile);
+                } else if let Some(config) = build_preset
+                    .as_ref()
+                    .and_then(|preset| preset.configuration.as_ref())
+                {
+                    cmd.arg("--config").arg(config);
+                }
+                cmd.arg("--prefix").arg(&dst).current_dir(&build);
+                run(
+                    &mut cmd,
+                    "cmake",
+                    Step::Install,
+                    quiet,
+                    capture,
+                    self.build_timeout,
+                )?;
+            }
+        }
+        let build_time = build_start.elapsed();
+
+        if !previous_manifest.is_empty() {
+            let manifest = read_install_manifest(&build);
+            for stale in previous_manifest.iter().filter(|f| !manifest.contains(f)) {
+                if !stale.starts_with(&dst) || fs::remove_file(stale).is_err() {
+                    continue;
+                }
+                // Clean up the directories the file was the last entry of.
+                let mut dir = stale.parent();
+                while let Some(d) = dir {
+                    if d == dst || fs::remove_dir(d).is_err() {
+                        break;
+                    }
+                    dir = d.parent();
+                }
+            }
+        }
+
+        if self.emit_timings {
+            self.print(&format!(
+                "cargo:warning=cmake timings: configure={} build={}",
+                format_duration(configure_time),
+                format_duration(build_time)
+            ));
+        }
+
+        if quiet {
+            let project = self
+                .path
+                .file_name()
+                .unwrap_or_else(|| self.path.as_os_str())
+                .to_string_lossy();
+            println!(
+                "cmake: built {} ({}) in {}",
+                project,
+                target_triple,
+                format_duration(start.elapsed())
+            );
+        }
+
+        if let Some(ref dir) = self.shared_libs_dir {
+            copy_shared_libs(&dst, dir)?;
+        }
+
+        for lib in &self.whole_archive_libs {
+            self.print(&format!(
+                "cargo:rustc-link-lib=static:+whole-archive={}",
+                lib
+            ));
+        }
+
+        for arg in &self.link_args {
+            self.print(&format!("cargo:rustc-link-arg={}", arg));
+        }
+
+        if self.header_only {
+            self.print(&format!("cargo:include={}", dst.join("include").display()));
+        } else {
+            // Libraries end up in `lib64` rather than `lib` on some
+            // distributions, and Windows import libraries are often installed
+            // next to the DLL.
+            self.print(&format!(
+                "cargo:rustc-link-search=native={}",
+                dst.join("lib").display()
+            ));
+            let mut extra_dirs = vec![dst.join("lib64")];
+            if target_triple.contains("windows") {
+                extra_dirs.push(dst.join("bin"));
+                extra_dirs.push(dst.clone());
+            }
+            for dir in extra_dirs.iter().filter(|dir| dir.is_dir()) {
+                self.print(&format!("cargo:rustc-link-search=native={}", dir.display()));
+            }
+        }
+        if let Some(ref path) = self.build_report {
+            let cmake_version = executable_version(&executable)
+                .map(|(major, minor, patch)| format!("{}.{}.{}", major, minor, patch));
+            let generator = cache_value(&build, "CMAKE_GENERATOR")
+                .or_else(|| generator.as_ref().map(|g| g.to_string_lossy().into_owned()));
+            let defines = toolchain_defines
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .chain(
+                    self.ordered_defines()
+                        .into_iter()
+                        .map(|(k, v)| (k.to_string_lossy().into_owned(), v.clone())),
+                )
+                .map(|(k, v)| {
+                    format!(
+                        "[{}, {}]",
+                        json_string(Some(&k)),
+                        json_string(Some(&v.to_string_lossy()))
+                    )
+                })
+                .collect::<Vec<_>>();
+            let flags = |flags: &OsString| json_string(Some(&flags.to_string_lossy()));
+            let report = format!(
+                "{{\n  \"version\": 1,\n  \"target\": {},\n  \"host\": {},\n  \
+                 \"cmake\": {{\"path\": {}, \"version\": {}}},\n  \"generator\": {},\n  \
+                 \"build_type\": {},\n  \"compilers\": {{\n    \"C\": {},\n    \"CXX\": {}\n  }},\n  \
+                 \"defines\": [{}],\n  \
+                 \"flags\": {{\"c\": {}, \"cxx\": {}, \"asm\": {}, \"ld\": {}}}\n}}\n",
+                json_string(Some(&target_triple)),
+                json_string(Some(&host)),
+                json_string(Some(&executable.to_string_lossy())),
+                json_string(cmake_version.as_deref()),
+                json_string(generator.as_deref()),
+                json_string(Some(&profile)),
+                compiler_report(&build, "C", &c_compiler),
+                compiler_report(&build, "CXX", &cxx_compiler),
+                defines.join(", "),
+                flags(&self.cflags),
+                flags(&self.cxxflags),
+                flags(&self.asmflags),
+                flags(&self.ldflags),
+            );
+            fs::write(path, report).map_err(|e| {
+                Error::Io(io::Error::new(
+                    e.kind(),
+                    format!("failed to write {}: {}", path.display(), e),
+                ))
+            })?;
+        }
+        let root = match self.root_metadata_path {
+            Some(ref path) => dst.join(path),
+            None => dst.clone(),
+        };
+        self.print(&format!("cargo:root={}", root.display()));
+        if !self.build_target_sequence.is_empty() && !installs {
+            return Ok(build);
+        }
+        Ok(dst)
+    }
+
+    // The `CMAKE_BUILD_TYPE` matching Cargo's profile, used unless `profile`
+    // was called.
+    fn default_profile(&self) -> String {
+        // Determine Rust's profile, optimization level, and debug info:
+        #[derive(PartialEq)]
+        enum RustProfile {
+            Debug,
+            Release,
+        }
+        #[derive(PartialEq, Debug)]
+        enum OptLevel {
+            Debug,
+            Release,
+            Size,
+        }
+
+        let rust_profile = match &self.getenv_unwrap("PROFILE")[..] {
+            "debug" => RustProfile::Debug,
+            "release" | "bench" => RustProfile::Release,
+            unknown => {
+                eprintln!(
+                    "Warning: unknown Rust profile={}; defaulting to a release build.",
+                    unknown
+                );
+                RustProfile::Release
+            }
+        };
+
+        let opt_level = match &self.getenv_unwrap("OPT_LEVEL")[..] {
+            "0" => OptLevel::Debug,
+            "1" | "2" | "3" => OptLevel::Release,
+            "s" | "z" => OptLevel::Size,
+            unknown => {
+                let default_opt_level = match rust_profile {
+                    RustProfile::Debug => OptLevel::Debug,
+                    RustProfile::Release => OptLevel::Release,
+                };
+                eprintln!(
+                    "Warning: unknown opt-level={}; defaulting to a {:?} build.",
+                    unknown, default_opt_level
+                );
+                default_opt_level
+            }
+        };
+
+        let debug_info: bool = match &self.getenv_unwrap("DEBUG")[..] {
+            "false" => false,
+            "true" => true,
+            unknown => {
+                eprintln!("Warning: unknown debug={}; defaulting to `true`.", unknown);
+                true
+            }
+        };
+
+        match (opt_level, debug_info) {
+            (OptLevel::Debug, _) => "Debug",
+            (OptLevel::Release, false) => "Release",
+            (OptLevel::Release, true) => "RelWithDebInfo",
+            (OptLevel::Size, _) => "MinSizeRel",
+        }
+        .to_string()
+    }
+
+    fn get_target_triple(&self) -> String {
+        match self.target.clone() {
+            Some(t) => t,
+            None => {
+                let mut t = self.getenv_unwrap("TARGET");
+                if t.ends_with("-darwin") && self.uses_cxx11 {
+                    t += "11"
+                }
+                t
+            }
+        }
+    }
+
+    fn get_host_triple(&self) -> String {
+        self.host
+            .clone()
+            .unwrap_or_else(|| self.getenv_unwrap("HOST"))
+    }
+
+    // Returns the C and C++ compilers the `cc` crate would use for the target,
+    // which are what we point cmake at.
+    fn compilers(&self, target_triple: &str, host: &str) -> (cc::Tool, cc::Tool) {
+        let ndk = self.uses_android_ndk();
+        let mut c_cfg = cc::Build::new();
+        c_cfg
+            .cargo_metadata(false)
+            .opt_level(0)
+            .debug(false)
+            .warnings(false)
+            .host(host)
+            .no_default_flags(ndk);
+        if !ndk {
+            c_cfg.target(target_triple);
+        }
+        let mut cxx_cfg = cc::Build::new();
+        cxx_cfg
+            .cargo_metadata(false)
+            .cpp(true)
+            .opt_level(0)
+            .debug(false)
+            .warnings(false)
+            .host(host)
+            .no_default_flags(ndk);
+        if !ndk {
+            cxx_cfg.target(target_triple);
+        }
+        if let Some(static_crt) = self.static_crt {
+            c_cfg.static_crt(static_crt);
+            cxx_cfg.static_crt(static_crt);
+        }
+        (c_cfg.get_compiler(), cxx_cfg.get_compiler())
+    }
+
+    fn dst_dir(&self) -> PathBuf {
+        self.out_dir
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(self.getenv_unwrap("OUT_DIR")))
+    }
+
+    // The source directory cmake is pointed at, see `copy_source_to_outdir`.
+    fn source_dir(&self) -> PathBuf {
+        if self.copy_source {
+            self.dst_dir().join("source")
+        } else {
+            self.path.clone()
+        }
+    }
+
+    fn build_dir(&self) -> PathBuf {
+        match self.existing_build_dir {
+            Some(ref dir) => dir.clone(),
+            None => self.dst_dir().join(&self.build_dir_name),
+        }
+    }
+
+    // The cache variables that describe the toolchain and the platform being
+    // targeted to cmake. These end up either on the command line or, when
+    // cross compiling, in a generated toolchain file.
+    fn toolchain_defines(
+        &self,
+        target_triple: &str,
+        host: &str,
+        c_compiler: &cc::Tool,
+        cxx_compiler: &cc::Tool,
+        generator: Option<&OsStr>,
+    ) -> Vec<(String, OsString)> {
+        let mut defines = Vec::new();
+        let msvc = target_triple.contains("msvc");
+        let is_ninja = generator.is_some_and(|g| g.to_string_lossy().contains("Ninja"));
+
+        if target_triple.contains("windows-gnu") {
+            if !host.contains("windows") {
+                // If we're cross compiling onto windows, then set some
+                // variables which will hopefully get things to succeed. Some
+                // systems may need the `windres` or `dlltool` variables set, so
+                // set them if possible.
+                if !self.defined("CMAKE_SYSTEM_NAME") {
+                    defines.push(("CMAKE_SYSTEM_NAME".to_string(), "Windows".into()));
+                }
+                if !self.defined("CMAKE_RC_COMPILER") {
+                    let exe = find_exe(c_compiler.path());
+                    if let Some(name) = exe.file_name().unwrap().to_str() {
+                        let name = name.replace("gcc", "windres");
+                        let windres = exe.with_file_name(name);
+                        if windres.is_file() {
+                            defines.push(("CMAKE_RC_COMPILER".to_string(), windres.into()));
+                        }
+                    }
+                }
+            }
+        } else if target_triple.contains("redox") {
+            if !self.defined("CMAKE_SYSTEM_NAME") {
+                defines.push(("CMAKE_SYSTEM_NAME".to_string(), "Generic".into()));
+            }
+        } else if target_triple.contains("solaris") && !self.defined("CMAKE_SYSTEM_NAME") {
+            defines.push(("CMAKE_SYSTEM_NAME".to_string(), "SunOS".into()));
+        }
+
+        defines.extend(get_target(target_triple).cmake_defines(self));
+
+        // Setting the system name is what puts cmake into cross compiling mode.
+        // Targets built on the same OS only change the architecture, which
+        // `cross_defaults` covers along with the rest of a cross setup.
+        let system_name = cmake_system_name(target_triple);
+        if system_name != cmake_system_name(host)
+            && !self.defined("CMAKE_SYSTEM_NAME")
+            && !defines.iter().any(|(k, _)| k == "CMAKE_SYSTEM_NAME")
+        {
+            if let Some(name) = system_name {
+                defines.push(("CMAKE_SYSTEM_NAME".to_string(), name.into()));
+            }
+        }
+
+        if self.cross_defaults && target_triple != host {
+            let cross = self.cross_defines(target_triple, host);
+            for (k, v) in cross {
+                if !defines.iter().any(|(a, _)| *a == k) {
+                    defines.push((k, v));
+                }
+            }
+        }
+
+        if self.sysroot_search_paths {
+            defines.extend(self.sysroot_defines(target_triple));
+        }
+
+        if self.try_compile_static && !self.defined("CMAKE_TRY_COMPILE_TARGET_TYPE") {
+            defines.push((
+                "CMAKE_TRY_COMPILE_TARGET_TYPE".to_string(),
+                "STATIC_LIBRARY".into(),
+            ));
+        }
+
+        if self.lto {
+            if !self.defined("CMAKE_INTERPROCEDURAL_OPTIMIZATION") {
+                defines.push((
+                    "CMAKE_INTERPROCEDURAL_OPTIMIZATION".to_string(),
+                    "ON".into(),
+                ));
+            }
+            for &(kind, compiler) in &[("C", c_compiler), ("CXX", cxx_compiler)] {
+                for tool in &["AR", "RANLIB"] {
+                    let var = format!("CMAKE_{}_COMPILER_{}", kind, tool);
+                    if self.defined(&var) || self.no_default_compiler {
+                        continue;
+                    }
+                    if let Some(path) = lto_tool(compiler.path(), &tool.to_lowercase()) {
+                        defines.push((var, path.into()));
+                    }
+                }
+            }
+        }
+
+        for &(kind, compiler) in &[
+            ("C", c_compiler),
+            ("CXX", cxx_compiler),
+            ("ASM", c_compiler),
+        ] {
+            let tool_var = format!("CMAKE_{}_COMPILER", kind);
+            // Apparently cmake likes to have an absolute path to the
+            // compiler as otherwise it sometimes thinks that this variable
+            // changed as it thinks the found compiler, /usr/bin/cc,
+            // differs from the specified compiler, cc. Not entirely sure
+            // what's up, but at least this means cmake doesn't get
+            // confused?
+            //
+            // Also specify this on Windows only if we use MSVC with Ninja,
+            // as it's not needed for MSVC with Visual Studio generators and
+            // for MinGW it doesn't really vary.
+            if !self.defined("CMAKE_TOOLCHAIN_FILE")
+                && !self.no_default_compiler
+                && !self.defined(&tool_var)
+                && (!host.contains("windows") || (msvc && is_ninja))
+            {
+                // CMake doesn't like unescaped `\`s in compiler paths
+                // so we either have to escape them or replace with `/`s.
+                let ccompiler = cmake_path(find_exe(compiler.path()).as_os_str());
+                defines.push((tool_var, ccompiler));
+            }
+        }
+
+        defines
+    }
+
+    // The settings enabled by `cross_defaults`, leaving out anything the user
+    // defined themselves.
+    fn cross_defines(&self, target_triple: &str, host: &str) -> Vec<(String, OsString)> {
+        let mut defines = Vec::new();
+        let mut arch = target_triple.split('-').next().unwrap_or("");
+        if arch == "i586" || arch == "i686" {
+            arch = "x86";
+        }
+        let system = cmake_system_name(target_triple).map(|s| s.to_string());
+
+        let mut cfg = cc::Build::new();
+        cfg.cargo_metadata(false)
+            .opt_level(0)
+            .debug(false)
+            .target(target_triple)
+            .host(host);
+        let ar = cfg.try_get_archiver().ok();
+        let ranlib = cfg.try_get_ranlib().ok();
+
+        let candidates = vec![
+            ("CMAKE_SYSTEM_NAME", system.map(OsString::from)),
+            ("CMAKE_SYSTEM_PROCESSOR", Some(arch.into())),
+            (
+                "CMAKE_AR",
+                ar.map(|ar| find_exe(Path::new(ar.get_program())).into()),
+            ),
+            (
+                "CMAKE_RANLIB",
+                ranlib.map(|ranlib| find_exe(Path::new(ranlib.get_program())).into()),
+            ),
+            ("CMAKE_FIND_ROOT_PATH_MODE_PROGRAM", Some("NEVER".into())),
+            ("CMAKE_FIND_ROOT_PATH_MODE_LIBRARY", Some("ONLY".into())),
+            ("CMAKE_FIND_ROOT_PATH_MODE_INCLUDE", Some("ONLY".into())),
+            ("CMAKE_FIND_ROOT_PATH_MODE_PACKAGE", Some("ONLY".into())),
+        ];
+        for (k, v) in candidates {
+            if let Some(v) = v {
+                if !self.defined(k) {
+                    defines.push((k.to_string(), v));
+                }
+            }
+        }
+        defines
+    }
+
+    // The search paths for the sysroot given as `CMAKE_SYSROOT`, if any, see
+    // `sysroot_search_paths`.
+    fn sysroot_defines(&self, target_triple: &str) -> Vec<(String, OsString)> {
+        let sysroot = match self
+            .defines
+            .iter()
+            .rev()
+            .find(|(k, _)| define_name(k) == "CMAKE_SYSROOT")
+        {
+            Some((_, v)) => PathBuf::from(v),
+            None => return Vec::new(),
+        };
+        let usr = sysroot.join("usr");
+        let multiarch = multiarch_triple(target_triple);
+        let dirs = |name: &str| {
+            let mut dirs = vec![usr.join(name)];
+            if let Some(ref multiarch) = multiarch {
+                dirs.push(usr.join(name).join(multiarch));
+            }
+            let dirs = dirs
+                .iter()
+                .map(|d| d.to_string_lossy().replace('\\', "/"))
+                .collect::<Vec<_>>();
+            OsString::from(dirs.join(";"))
+        };
+
+        let mut defines = Vec::new();
+        if !self.defined("CMAKE_FIND_ROOT_PATH") {
+            defines.push(("CMAKE_FIND_ROOT_PATH".to_string(), sysroot.clone().into()));
+        }
+        if !self.defined("CMAKE_INCLUDE_PATH") {
+            defines.push(("CMAKE_INCLUDE_PATH".to_string(), dirs("include")));
+        }
+        if !self.defined("CMAKE_LIBRARY_PATH") {
+            defines.push(("CMAKE_LIBRARY_PATH".to_string(), dirs("lib")));
+        }
+        defines
+    }
+
+    // Points cmake at the NDK's toolchain file for Android targets, see
+    // `android_ndk`.
+    fn add_android_defines(&mut self) -> Result<(), Error> {
+        let target_triple = self.get_target_triple();
+        if !target_triple.contains("-android") || self.defined("CMAKE_TOOLCHAIN_FILE") {
+            return Ok(());
+        }
+        let ndk = match self
+            .android_ndk
+            .clone()
+            .or_else(|| self.getenv_os("ANDROID_NDK_HOME").map(PathBuf::from))
+        {
+            Some(ndk) => ndk,
+            None => return Ok(()),
+        };
+        let abi = match target_triple.split('-').next().unwrap_or("") {
+            "aarch64" => "arm64-v8a",
+            arch if arch.starts_with("arm") || arch.starts_with("thumbv7") => "armeabi-v7a",
+            "i686" => "x86",
+            "x86_64" => "x86_64",
+            arch => {
+                return Err(Error::Config(format!(
+                    "no Android ABI known for the {} architecture",
+                    arch
+                )))
+            }
+        };
+        let toolchain = ndk.join("build/cmake/android.toolchain.cmake");
+        self.defines
+            .push(("CMAKE_TOOLCHAIN_FILE".into(), toolchain.into()));
+        if !self.defined("ANDROID_ABI") {
+            self.defines.push(("ANDROID_ABI".into(), abi.into()));
+        }
+        if let Some(level) = self.android_api_level {
+            if !self.defined("ANDROID_PLATFORM") {
+                self.defines.push((
+                    "ANDROID_PLATFORM".into(),
+                    format!("android-{}", level).into(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Whether a `*-musl` target is linked statically, see `musl_static`.
+    fn links_musl_statically(&self, target_triple: &str) -> bool {
+        target_triple.contains("musl")
+            && self.musl_static.unwrap_or_else(|| {
+                self.getenv_os("CARGO_CFG_TARGET_FEATURE")
+                    .is_some_and(|f| f.to_string_lossy().split(',').any(|f| f == "crt-static"))
+            })
+    }
+
+    // Adds the defaults for statically linking against musl, and the musl
+    // sysroot, unless the user defined them already.
+    fn add_musl_defines(&mut self, target_triple: &str, c_compiler: &cc::Tool) {
+        if !target_triple.contains("musl") {
+            return;
+        }
+        if self.links_musl_statically(target_triple) {
+            if !self.defined("BUILD_SHARED_LIBS") {
+                self.defines
+                    .push(("BUILD_SHARED_LIBS".into(), "OFF".into()));
+            }
+            if !self.defined("CMAKE_FIND_LIBRARY_SUFFIXES") {
+                self.defines
+                    .push(("CMAKE_FIND_LIBRARY_SUFFIXES".into(), ".a".into()));
+            }
+        }
+        if self.defined("CMAKE_SYSROOT") || self.defined("CMAKE_TOOLCHAIN_FILE") {
+            return;
+        }
+        let sysroot = self.musl_sysroot.clone().or_else(|| {
+            let output = c_compiler
+                .to_command()
+                .arg("-print-sysroot")
+                .stderr(Stdio::null())
+                .output()
+                .ok()?;
+            let sysroot = PathBuf::from(String::from_utf8(output.stdout).ok()?.trim());
+            if output.status.success() && sysroot.is_dir() && sysroot != Path::new("/") {
+                Some(sysroot)
+            } else {
+                None
+            }
+        });
+        if let Some(sysroot) = sysroot {
+            self.defines.push(("CMAKE_SYSROOT".into(), sysroot.into()));
+        }
+    }
+
+    // Points cmake at the WASI SDK's toolchain file for `wasm32-wasi*` targets,
+    // see `wasi_sdk`.
+    fn add_wasi_defines(&mut self) -> Result<(), Error> {
+        let target_triple = self.get_target_triple();
+        if !target_triple.starts_with("wasm32-wasi") || self.defined("CMAKE_TOOLCHAIN_FILE") {
+            return Ok(());
+        }
+        let sdk = match self
+            .wasi_sdk
+            .clone()
+            .or_else(|| self.getenv_os("WASI_SDK_PATH").map(PathBuf::from))
+        {
+            Some(sdk) => sdk,
+            None => {
+                return Err(Error::Config(format!(
+                    "building for {} requires the WASI SDK\n\
+                     set WASI_SDK_PATH to the directory it's installed in, or \
+                     pass a toolchain file with `define_toolchain_file`",
+                    target_triple
+                )))
+            }
+        };
+        let cmake_dir = sdk.join("share/cmake");
+        let variant = if target_triple.contains("threads") {
+            "wasi-sdk-pthread.cmake"
+        } else if target_triple.starts_with("wasm32-wasip2") {
+            "wasi-sdk-p2.cmake"
+        } else {
+            "wasi-sdk.cmake"
+        };
+        // Older SDKs only ship the plain toolchain file.
+        let mut toolchain = cmake_dir.join(variant);
+        if !toolchain.is_file() {
+            toolchain = cmake_dir.join("wasi-sdk.cmake");
+        }
+        self.defines
+            .push(("CMAKE_TOOLCHAIN_FILE".into(), toolchain.into()));
+        if !self.defined("WASI_SDK_PREFIX") {
+            self.defines.push(("WASI_SDK_PREFIX".into(), sdk.into()));
+        }
+        Ok(())
+    }
+
+    // The user's defines with those selecting the toolchain first, see
+    // `define`.
+    fn ordered_defines(&self) -> Vec<&(OsString, OsString)> {
+        let (mut first, rest): (Vec<_>, Vec<_>) = self
+            .defines
+            .iter()
+            .partition(|(k, _)| is_toolchain_define(&k.to_string_lossy()));
+        first.extend(rest);
+        first
+    }
+
+    // A new command running the same program as `cmd` in the same
+    // environment, but without its arguments.
+    fn command_like(&self, cmd: &Command) -> Command {
+        let mut new = Command::new(cmd.get_program());
+        self.clear_env(&mut new);
+        for (k, v) in cmd.get_envs() {
+            match v {
+                Some(v) => new.env(k, v),
+                None => new.env_remove(k),
+            };
+        }
+        new
+    }
+
+    // The libraries installed into `dst` as `cargo:rustc-link-lib` takes them,
+    // see `build_libs`.
+    fn link_libs(&self, dst: &Path) -> Vec<String> {
+        installed_libs(dst)
+            .into_iter()
+            .filter(|(shared, _)| {
+                if *shared {
+                    self.list_shared_libs
+                } else {
+                    self.list_static_libs
+                }
+            })
+            .map(|(shared, name)| format!("{}={}", if shared { "dylib" } else { "static" }, name))
+            .collect()
+    }
+
+    // Clears the environment of `cmd` if `env_clear` is enabled, keeping the
+    // variables needed to find and run the tools at all.
+    fn clear_env(&self, cmd: &mut Command) {
+        if !self.env_clear {
+            return;
+        }
+        cmd.env_clear();
+        let essential: &[&str] = if cfg!(windows) {
+            &["PATH", "SystemRoot", "TEMP", "TMP"]
+        } else {
+            &["PATH"]
+        };
+        for var in essential {
+            if let Some(value) = env::var_os(var) {
+                cmd.env(var, value);
+            }
+        }
+    }
+
+    // Whether the toolchain settings should go through a generated toolchain
+    // file, which we only do when cross compiling and the user hasn't brought
+    // their own toolchain file.
+    fn uses_generated_toolchain_file(&self, target_triple: &str, host: &str) -> bool {
+        self.generated_toolchain_file
+            && target_triple != host
+            && !self.defined("CMAKE_TOOLCHAIN_FILE")
+            && self.getenv_os("CMAKE_TOOLCHAIN_FILE").is_none()
+    }
+
+    // Prints `cargo:rerun-if-changed` for each file of the source tree that
+    // isn't excluded. Files rather than directories are printed as cargo would
+    // otherwise also watch the excluded parts of a directory.
+    // The Cargo target directory, which Cargo writes to after the build script
+    // has run: `CARGO_TARGET_DIR` if set, and the directory holding the
+    // profile directory `OUT_DIR` is in, e.g. `target` for
+    // `target/debug/build/foo-1234/out`.
+    fn cargo_target_dirs(&self) -> Vec<PathBuf> {
+        let mut dirs = Vec::new();
+        if let Some(dir) = self.getenv_os("CARGO_TARGET_DIR") {
+            dirs.push(env::current_dir().unwrap().join(dir));
+        }
+        if let Some(out_dir) = self.getenv_os("OUT_DIR") {
+            let out_dir = PathBuf::from(out_dir);
+            let target = out_dir
+                .ancestors()
+                .find(|dir| dir.file_name() == Some(OsStr::new("build")))
+                .and_then(|build| build.parent()?.parent());
+            dirs.extend(target.map(Path::to_path_buf));
+        }
+        dirs
+    }
+
+    fn print_rerun_if_changed(&self, dst: &Path) -> Result<(), Error> {
+        let mut prefixes = vec![dst.to_path_buf()];
+        prefixes.extend(self.cargo_target_dirs());
+        let mut name_globs = Vec::new();
+        let mut path_globs = Vec::new();
+        for pattern in &self.rerun_excludes {
+            let pattern = pattern.to_string_lossy().replace('\\', "/");
+            if !pattern.contains(['*', '?', '[']) {
+                prefixes.push(self.path.join(&pattern));
+            } else if pattern.contains('/') {
+                path_globs.push(glob_to_regex(&pattern)?);
+            } else {
+                name_globs.push(glob_to_regex(&pattern)?);
+            }
+        }
+
+        let mut files = Vec::new();
+        let mut dirs = vec![self.path.clone()];
+        while let Some(dir) = dirs.pop() {
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(..) => continue,
+            };
+            for entry in entries.filter_map(|e| e.ok()) {
+                let path = entry.path();
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let relative = path
+                    .strip_prefix(&self.path)
+                    .unwrap_or(&path)
+                    .to_string_lossy()
+                    .replace('\\', "/");
+                if name == ".git"
+                    || name == ".hg"
+                    || name == ".svn"
+                    || prefixes.iter().any(|p| path.starts_with(p))
+                    || name_globs.iter().any(|g| g.is_match(&name))
+                    || path_globs.iter().any(|g| g.is_match(&relative))
+                {
+                    continue;
+                }
+                // Symlinked directories aren't followed to avoid cycles.
+                match entry.file_type() {
+                    Ok(ty) if ty.is_dir() => dirs.push(path),
+                    Ok(..) => files.push(path),
+                    Err(..) => {}
+                }
+            }
+        }
+        files.sort();
+        for file in files {
+            self.print(&format!("cargo:rerun-if-changed={}", file.display()));
+        }
+        Ok(())
+    }
+
+    // Fails early if the native build tool of the generator isn't installed,
+    // rather than after a successful configure.
+    fn check_build_tool(
+        &self,
+        generator: Option<&OsStr>,
+        target_triple: &str,
+    ) -> Result<(), Error> {
+        if self.preset.is_some() && generator.is_none() {
+            // The generator comes from the preset, which we don't parse.
+            return Ok(());
+        }
+        if let Some(program) = self
+            .defines
+            .iter()
+            .find(|(k, _)| define_name(k) == "CMAKE_MAKE_PROGRAM")
+            .map(|(_, v)| PathBuf::from(v))
+        {
+            if !program.is_file() && !find_on_path(&program.to_string_lossy()) {
+                return Err(Error::Config(format!(
+                    "CMAKE_MAKE_PROGRAM {} was not found",
+                    program.display()
+                )));
+            }
+            return Ok(());
+        }
+        let kind = generator_kind(generator, target_triple);
+        let generator = match generator {
+            Some(generator) => generator.to_string_lossy().into_owned(),
+            None if target_triple.contains("msvc") => return Ok(()),
+            None => String::new(),
+        };
+        if generator.is_empty() && cfg!(windows) && !target_triple.contains("windows-gnu") {
+            // cmake's default generator on Windows varies with what's installed.
+            return Ok(());
+        }
+        let tool = match kind {
+            Some(GeneratorKind::Ninja) => "ninja",
+            Some(GeneratorKind::Xcode) => "xcodebuild",
+            Some(GeneratorKind::NMake) if generator.contains("JOM") => "jom",
+            Some(GeneratorKind::NMake) => "nmake",
+            Some(GeneratorKind::Make) if generator.contains("MinGW") => "mingw32-make",
+            // MSYS and MinGW makefiles are picked based on what's installed.
+            Some(GeneratorKind::Make) if target_triple.contains("windows-gnu") => return Ok(()),
+            Some(GeneratorKind::Make) => "make",
+            // cmake locates msbuild through the Visual Studio installation.
+            Some(GeneratorKind::VisualStudio) | None => return Ok(()),
+        };
+        if !find_on_path(tool) {
+            let generator = if generator.is_empty() {
+                "Unix Makefiles"
+            } else {
+                &generator
+            };
+            return Err(Error::Config(format!(
+                "generator {} requires {} which was not found",
+                generator, tool
+            )));
+        }
+        Ok(())
+    }
+
+    // The arguments passed after the defines, from `configure_arg` and then
+    // `CMAKE_ARGS`.
+    fn extra_configure_args(&self) -> Vec<OsString> {
+        let mut args = self.configure_args.clone();
+        // Packagers pass extra arguments for every cmake build this way.
+        if let Some(env) = self.getenv_os("CMAKE_ARGS") {
+            args.extend(
+                split_args(&env.to_string_lossy())
+                    .into_iter()
+                    .map(OsString::from),
+            );
+        }
+        args
+    }
+
+    // The generator set with `generator`, or else from `CMAKE_GENERATOR`.
+    fn generator_name(&self) -> Option<OsString> {
+        self.generator.clone().or_else(|| {
+            self.getenv_os("CMAKE_GENERATOR")
+                .filter(|generator| !generator.is_empty())
+        })
+    }
+
+    fn visual_studio_generator(&self, target: &str) -> String {
+        match cc::windows_registry::find_vs_version() {
+            Ok(version) => match visual_studio_generator_for(version, target) {
+                Ok(generator) => generator.to_string(),
+                Err(msg) => panic!("{}", msg),
+            },
+            Err(msg) => panic!("{}", msg),
+        }
+    }
+
+    fn cmake_executable(&self) -> OsString {
+        self.getenv_os("CMAKE")
+            .unwrap_or_else(|| OsString::from("cmake"))
+    }
+
+    // All environment lookups go through here so that, with
+    // `register_rerun_if_changed`, cargo is told to rerun the build script
+    // whenever one of the variables we consulted changes. Variables read
+    // before the option is turned on are reported by `print_env_reruns`.
+    fn getenv_os(&self, v: &str) -> Option<OsString> {
+        let mut cache = self.env_cache.lock().unwrap();
+        if let Some(val) = cache.get(v) {
+            return val.clone();
+        }
+        if self.rerun_if_changed {
+            self.print_env_rerun(v);
+        }
+        let r = env::var_os(v);
+        cache.insert(v.to_string(), r.clone());
+        r
+    }
+
+    // Prints `cargo:rerun-if-env-changed` for every variable looked up so far,
+    // e.g. by `map_feature` or `get_target`.
+    fn print_env_reruns(&self) {
+        let mut vars = self
+            .env_cache
+            .lock()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        vars.sort();
+        for v in vars {
+            self.print_env_rerun(&v);
+        }
+    }
+
+    fn print_env_rerun(&self, v: &str) {
+        // `PATH` is only used to locate tools, and tends to differ between
+        // shells, so changes to it shouldn't cause a rebuild.
+        if v == "PATH" {
+            return;
+        }
+        let line = format!("cargo:rerun-if-env-changed={}", v);
+        if !self.metadata.lock().unwrap().contains(&line) {
+            self.print(&line);
+        }
+    }
+
+    fn getenv(&self, v: &str) -> Option<String> {
+        self.getenv_os(v).and_then(|s| s.into_string().ok())
+    }
+
+    // Checks up front that the variables Cargo sets for build scripts are
+    // there, unless they've been overridden, so that their absence can be
+    // reported as an error instead of a panic halfway through.
+    fn check_env(&self) -> Result<(), Error> {
+        let mut required = Vec::new();
+        if self.target.is_none() {
+            required.push("TARGET");
+        }
+        if self.host.is_none() {
+            required.push("HOST");
+        }
+        if self.out_dir.is_none() {
+            required.push("OUT_DIR");
+        }
+        if self.profile.is_none() {
+            required.extend(["PROFILE", "OPT_LEVEL", "DEBUG"]);
+        }
+        match required.into_iter().find(|v| self.getenv(v).is_none()) {
+            Some(var) => Err(Error::MissingEnv {
+                var: var.to_string(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn getenv_unwrap(&self, v: &str) -> String {
+        match self.getenv(v) {
+            Some(s) => s,
+            None => fail(&format!("environment variable `{}` not defined", v)),
+        }
+    }
+
+    fn print(&self, s: &str) {
+        self.metadata.lock().unwrap().push(s.to_string());
+        if self.cargo_metadata {
+            println!("{}", s);
+        }
+    }
+
+    fn dump_metadata(&self) {
+        if let Some(ref file) = self.cargo_directives_file {
+            let mut contents = String::new();
+            for line in self.metadata.lock().unwrap().iter() {
+                contents.push_str(line);
+                contents.push('\n');
+            }
+            if let Err(e) = fs::write(file, contents) {
+                fail(&format!("failed to write {}: {}", file.display(), e));
+            }
+        }
+    }
+
+    // Warns when the build runs translated by Rosetta on Apple silicon, where
+    // cmake and the compilers default to x86_64 even for a native target.
+    fn check_rosetta(&self, target_triple: &str) {
+        if !cfg!(target_os = "macos") || !target_triple.contains("apple-darwin") {
+            return;
+        }
+        let sysctl = |name: &str| {
+            Command::new("sysctl")
+                .arg("-n")
+                .arg(name)
+                .output()
+                .ok()
+                .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        };
+        if sysctl("sysctl.proc_translated").as_deref() != Some("1") {
+            return;
+        }
+        let target_arch = target_triple.split('-').next().unwrap_or("");
+        self.print(&format!(
+            "cargo:warning=the build is running under Rosetta on an arm64 Mac, so \
+             cmake and the compilers run as x86_64{}; use a native arm64 Rust \
+             toolchain (e.g. `rustup default stable-aarch64-apple-darwin`) to avoid \
+             mismatched architectures",
+            if target_arch == "aarch64" {
+                " while the target is arm64"
+            } else {
+                ""
+            }
+        ));
+    }
+
+    fn check_universal_macos(&self, generator: Option<&OsStr>) -> Result<(), Error> {
+        let target_triple = self.get_target_triple();
+        if !target_triple.contains("apple-darwin") {
+            return Err(Error::Config(format!(
+                "universal macOS builds are not supported for target {}",
+                target_triple
+            )));
+        }
+        if let Some(generator) = generator {
+            let generator = generator.to_string_lossy();
+            if !(generator == "Xcode"
+                || generator.contains("Ninja")
+                || generator.contains("Makefiles"))
+            {
+                return Err(Error::Config(format!(
+                    "the {} generator can't produce universal macOS binaries",
+                    generator
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // Removes all but the last definition of each variable, matching the
+    // value cmake itself would end up using.
+    fn dedup_defines(&mut self) {
+        let mut defines = Vec::with_capacity(self.defines.len());
+        for (k, v) in std::mem::take(&mut self.defines).into_iter().rev() {
+            if defines
+                .iter()
+                .any(|(a, _): &(OsString, OsString)| define_name(a) == define_name(&k))
+            {
+                self.print(&format!(
+                    "cargo:warning=cmake variable `{}` was defined more than once; \
+                     using the last value",
+                    k.to_string_lossy()
+                ));
+                continue;
+            }
+            defines.push((k, v));
+        }
+        defines.reverse();
+        self.defines = defines;
+    }
+
+    fn defined(&self, var: &str) -> bool {
+        self.defines.iter().any(|(a, _)| define_name(a) == var)
+    }
+
+    // If a cmake project has previously been built (e.g. CMakeCache.txt already
+    // exists), then cmake will choke if the source directory for the original
+    // project being built has changed. Detect this situation through the
+    // `CMAKE_HOME_DIRECTORY` variable that cmake emits and if it doesn't match
+    // we blow away the build directory and start from scratch (the recommended
+    // solution apparently [1]).
+    //
+    // [1]: https://cmake.org/pipermail/cmake/2012-August/051545.html
+    fn maybe_clear(&self, dir: &Path) {
+        // CMake will apparently store canonicalized paths which normally
+        // isn't relevant to us but we canonicalize it here to ensure
+        // we're both checking the same thing.
+        let source = self.source_dir();
+        let path = fs::canonicalize(&source).unwrap_or(source);
+        let mut f = match File::open(dir.join("CMakeCache.txt")) {
+            Ok(f) => f,
+            Err(..) => return,
+        };
+        let mut u8contents = Vec::new();
+        match f.read_to_end(&mut u8contents) {
+            Ok(f) => f,
+            Err(..) => return,
+        };
+        let contents = String::from_utf8_lossy(&u8contents);
+        drop(f);
+        for line in contents.lines() {
+            if line.starts_with("CMAKE_HOME_DIRECTORY") {
+                let needs_cleanup = match line.split('=').next_back() {
+                    Some(cmake_home) => fs::canonicalize(cmake_home)
+                        .ok()
+                        .map(|cmake_home| cmake_home != path)
+                        .unwrap_or(true),
+                    None => true,
+                };
+                if needs_cleanup {
+                    println!(
+                        "detected home dir change, cleaning out entire build \
+                         directory"
+                    );
+                    fs::remove_dir_all(dir).unwrap();
+                }
+                break;
+            }
+        }
+    }
+}
+
+trait Target {
+    fn cmake_defines(&self, _config: &Config) -> Vec<(String, OsString)> {
+        Vec::new()
+    }
+
+    fn should_exclude_env_var(&self, _key: &OsStr, _value: &OsStr) -> bool {
+        false
+    }
+
+    fn filter_compiler_args(&self, _flags: &mut OsString) {}
+}
+
+fn get_target(target_triple: &str) -> Box<dyn Target> {
+    let target: Option<Box<dyn Target>> = AppleTarget::new(target_triple)
+        .map(|apple_target| Box::new(apple_target) as Box<dyn Target>);
+    target.unwrap_or_else(|| Box::new(GenericTarget::new(target_triple)))
+}
+
+struct GenericTarget {}
+
+impl GenericTarget {
+    fn new(_target_triple: &str) -> GenericTarget {
+        GenericTarget {}
+    }
+}
+
+impl Target for GenericTarget {}
+
+struct AppleTarget {
+    rust_target: String,
+    rust_target_arch: String,
+    rust_target_platform: String,
+    simulator: bool,
+}
+
+impl AppleTarget {
+    fn new(target_triple: &str) -> Option<AppleTarget> {
+        let parts: Vec<&str> = target_triple.split('-').collect();
+        if parts.len() < 3 {
+            return None;
+        }
+
+        let rust_target_arch = parts[0];
+        let rust_target_vendor = parts[1];
+        let rust_target_platform = parts[2];
+
+        if rust_target_vendor != "apple" {
+            return None;
+        }
+        if rust_target_platform != "ios" && !rust_target_platform.starts_with("darwin") {
+            eprintln!(
+                "Warning: unknown Apple platform ({}) for target: {}",
+                rust_target_platform, target_triple
+            );
+            return None;
+        }
+
+        Some(AppleTarget {
+            rust_target: target_triple.to_owned(),
+            rust_target_arch: rust_target_arch.to_owned(),
+            rust_target_platform: rust_target_platform.to_owned(),
+            simulator: parts.get(3) == Some(&"sim"),
+        })
+    }
+
+    fn is_ios_target(&self) -> bool {
+        self.rust_target_platform == "ios"
+    }
+
+    fn is_osx_target(&self) -> bool {
+        self.rust_target_platform.starts_with("darwin")
+    }
+
+    fn cmake_target_arch(&self) -> Option<String> {
+        match self.rust_target_arch.as_str() {
+            "aarch64" => Some("arm64".to_owned()),
+            "armv7" => Some("armv7".to_owned()),
+            "armv7s" => Some("armv7s".to_owned()),
+            "i386" => Some("i386".to_owned()),
+            "x86_64" => Some("x86_64".to_owned()),
+            _ => {
+                eprintln!(
+                    "Warning: unknown architecture for target: {}",
+                    self.rust_target_arch
+                );
+                None
+            }
+        }
+    }
+
+    fn sdk_name(&self) -> Option<String> {
+        if self.is_ios_target() {
+            match self.rust_target_arch.as_str() {
+                // `aarch64-apple-ios-sim` is the simulator on Apple silicon.
+                _ if self.simulator => Some("iphonesimulator".to_owned()),
+                "aarch64" | "armv7" | "armv7s" => Some("iphoneos".to_owned()),
+                "i386" | "x86_64" => Some("iphonesimulator".to_owned()),
+                _ => {
+                    eprintln!(
+                        "Warning: unknown architecture for Apple target: {}",
+                        self.rust_target_arch
+                    );
+                    None
+                }
+            }
+        } else if self.is_osx_target() {
+            Some("macosx".to_owned())
+        } else {
+            eprintln!(
+                "Warning: could not determine sdk name for Apple target: {}",
+                self.rust_target
+            );
+            None
+        }
+    }
+
+    fn deployment_target(&self, config: &Config) -> Option<String> {
+        if let Some(ref version) = config.osx_deployment_target {
+            Some(version.clone())
+        } else if self.is_ios_target() {
+            Some(
+                config
+                    .getenv("IPHONEOS_DEPLOYMENT_TARGET")
+                    .unwrap_or_else(|| "7.0".into()),
+            )
+        } else if self.is_osx_target() {
+            Some(
+                config
+                    .getenv("MACOSX_DEPLOYMENT_TARGET")
+                    .unwrap_or_else(|| "".into()),
+            )
+        } else {
+            eprintln!(
+                "Warning: could not determine deployment target for Apple target: {}",
+                self.rust_target
+            );
+            None
+        }
+    }
+}
+
+impl Target for AppleTarget {
+    fn cmake_defines(&self, config: &Config) -> Vec<(String, OsString)> {
+        let mut defines = Vec::new();
+
+        // These 3 CMAKE_OSX_* variables apply to all Apple platforms
+
+        if !config.defined("CMAKE_OSX_ARCHITECTURES") {
+            if let Some(cmake_target_arch) = self.cmake_target_arch() {
+                defines.push((
+                    "CMAKE_OSX_ARCHITECTURES".to_string(),
+                    cmake_target_arch.into(),
+                ));
+            }
+        }
+
+        if !config.defined("CMAKE_OSX_SYSROOT") {
+            if let Some(sdk_name) = config.osx_sysroot.clone().or_else(|| self.sdk_name()) {
+                let sysroot = match xcrun_sdk_path(&sdk_name) {
+                    Some(path) => path.into_os_string(),
+                    None => sdk_name.into(),
+                };
+                defines.push(("CMAKE_OSX_SYSROOT".to_string(), sysroot));
+            }
+        }
+
+        if !config.defined("CMAKE_OSX_DEPLOYMENT_TARGET") {
+            if let Some(deployment_target) = self.deployment_target(config) {
+                defines.push((
+                    "CMAKE_OSX_DEPLOYMENT_TARGET".to_string(),
+                    deployment_target.into(),
+                ));
+            }
+        }
+
+        // CMAKE_SYSTEM_NAME is used to tell cmake we're cross-compiling
+        if self.is_ios_target() && !config.defined("CMAKE_SYSTEM_NAME") {
+            defines.push(("CMAKE_SYSTEM_NAME".to_string(), "iOS".into()));
+        }
+
+        defines
+    }
+
+    fn should_exclude_env_var(&self, key: &OsStr, _value: &OsStr) -> bool {
+        key.to_str().is_some_and(|key| {
+            // These cause issues with llvm if an env var for a different Apple platform
+            // is present. Since cmake handles communicating these values to llvm, and
+            // we use cmake defines to tell cmake what the value is, the env vars themselves
+            // are filtered out.
+            key.ends_with("DEPLOYMENT_TARGET") || key.starts_with("SDK")
+        })
+    }
+
+    fn filter_compiler_args(&self, flags: &mut OsString) {
+        if let Some(flags_str) = flags.to_str() {
+            lazy_static! {
+                static ref ARCH_REGEX: Regex = Regex::new("-arch [^ ]+ ").unwrap();
+                static ref DEPLOYMENT_TARGET_REGEX: Regex =
+                    Regex::new("-m[\\w-]+-version-min=[\\d.]+ ").unwrap();
+                static ref SYSROOT_REGEX: Regex = Regex::new("-isysroot [^ ]+ ").unwrap();
+            }
+
+            let mut flags_string = flags_str.to_owned();
+            flags_string.push(' ');
+
+            // These are set by cmake
+            flags_string = ARCH_REGEX.replace(&flags_string, "").into_owned();
+            flags_string = DEPLOYMENT_TARGET_REGEX
+                .replace(&flags_string, "")
+                .into_owned();
+            flags_string = SYSROOT_REGEX.replace(&flags_string, "").into_owned();
+
+            if flags_string.ends_with(' ') {
+                flags_string.pop();
+            }
+
+            flags.clear();
+            flags.push(OsString::from(flags_string));
+        }
+    }
+}
+
+// Appends `flag` to the flags of the given configuration, whose name is
+// normalized to the uppercase form cmake uses in variable names.
+fn push_config_flag(config_flags: &mut Vec<(String, OsString)>, config: &str, flag: &OsStr) {
+    if config.is_empty()
+        || !config
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        panic!("invalid cmake configuration name: {:?}", config);
+    }
+    let config = config.to_uppercase();
+    match config_flags.iter_mut().find(|(c, _)| *c == config) {
+        Some((_, flags)) => {
+            flags.push(" ");
+            flags.push(flag);
+        }
+        None => config_flags.push((config, flag.to_owned())),
+    }
+}
+
+// Translates a glob into an anchored regex, where `*` and `?` don't match
+// across `/` but `**` does.
+fn glob_to_regex(glob: &str) -> Result<Regex, Error> {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                regex.push('[');
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                    if c == '\\' || c == '[' {
+                        regex.push('\\');
+                    }
+                    regex.push(c);
+                }
+                regex.push(']');
+            }
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    Regex::new(&regex)
+        .map_err(|e| Error::Config(format!("invalid rerun_exclude pattern `{}`: {}", glob, e)))
+}
+
+// Rejects keys that can't be expressed as `-DKEY=value`.
+fn check_define_key(key: &OsStr) {
+    let key = key.to_string_lossy();
+    if key.is_empty() {
+        fail("cmake variable names passed to `define` must not be empty");
+    }
+    if key.contains('=') {
+        fail(&format!(
+            "invalid cmake variable name `{}`: names passed to `define` can't contain `=`",
+            key
+        ));
+    }
+}
+
+// The name of the variable in a define key, which may also give its type as
+// in `NAME:BOOL`.
+fn define_name(key: &OsStr) -> String {
+    let key = key.to_string_lossy();
+    key.split(':').next().unwrap_or("").to_string()
+}
+
+// A path as cmake prefers it, with forward slashes on Windows.
+fn cmake_path(path: &OsStr) -> OsString {
+    #[cfg(windows)]
+    {
+        use std::os::windows::ffi::{OsStrExt, OsStringExt};
+        let wchars = path
+            .encode_wide()
+            .map(|wchar| {
+                if wchar == b'\\' as u16 {
+                    '/' as u16
+                } else {
+                    wchar
+                }
+            })
+            .collect::<Vec<_>>();
+        OsString::from_wide(&wchars)
+    }
+    #[cfg(not(windows))]
+    {
+        path.to_owned()
+    }
+}
+
+fn define_arg(key: &str, value: &OsStr) -> OsString {
+    let mut arg = OsString::from("-D");
+    arg.push(key);
+    arg.push("=");
+    arg.push(value);
+    arg
+}
+
+// Writes `defines` as a cmake toolchain file into `dir`. The file is only
+// rewritten when its contents change so that cmake doesn't needlessly
+// reconfigure.
+fn write_toolchain_file(
+    dir: &Path,
+    target_triple: &str,
+    defines: &[(String, OsString)],
+) -> Result<PathBuf, Error> {
+    let mut contents = format!(
+        "# Generated by the cmake crate for the {} target.\n",
+        target_triple
+    );
+    for (k, v) in defines {
+        contents.push_str(&format!(
+            "set({} \"{}\")\n",
+            k,
+            cmake_quote(&v.to_string_lossy())
+        ));
+    }
+    let path = dir.join("cmake-rs-toolchain.cmake");
+    write_if_changed(&path, &contents)?;
+    Ok(path)
+}
+
+// Writes a generated file, leaving it untouched if it already has the right
+// contents so that cmake doesn't consider the build out of date.
+fn write_if_changed(path: &Path, contents: &str) -> Result<(), Error> {
+    if fs::read_to_string(path).ok().as_deref() != Some(contents) {
+        fs::write(path, contents).map_err(|e| io_context("write", path, e))?;
+    }
+    Ok(())
+}
+
+// Escapes a value for use inside a quoted cmake argument.
+fn cmake_quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == '\\' || c == '"' || c == '$' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted
+}
+
+// A file copied into the source tree for the duration of a build, which is
+// removed again when this is dropped.
+struct StagedFile {
+    path: PathBuf,
+}
+
+impl StagedFile {
+    fn presets(source: &Path, presets: &Path) -> Result<StagedFile, Error> {
+        let contents = fs::read(presets).map_err(|e| io_context("read", presets, e))?;
+        // Existing files with the same contents are most likely leftovers
+        // from a build that was killed before it could clean up after itself.
+        let project_presets = source.join("CMakePresets.json");
+        let path = match fs::read(&project_presets) {
+            Ok(ref existing) if *existing != contents => source.join("CMakeUserPresets.json"),
+            _ => project_presets,
+        };
+        if let Ok(existing) = fs::read(&path) {
+            if existing != contents {
+                return Err(Error::Config(format!(
+                    "cannot use the presets in {} as {} already exists",
+                    presets.display(),
+                    path.display()
+                )));
+            }
+        }
+        fs::write(&path, contents).map_err(|e| io_context("write", &path, e))?;
+        Ok(StagedFile { path })
+    }
+}
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Looks up the value of a variable in the `CMakeCache.txt` of `build`, where
+// entries have the form `NAME:TYPE=value`.
+fn cache_value(build: &Path, name: &str) -> Option<String> {
+    let cache = read_cache(build).ok()?;
+    cache
+        .get(name)
+        .filter(|value| !value.is_empty())
+        .map(|value| value.to_string())
+}
+
+// Fails early with a clear message if files can't be installed into `dst`,
+// which otherwise shows up as an obscure error halfway through the install.
+fn check_install_dir(dst: &Path) -> Result<(), Error> {
+    let probe = dst.join(".cmake-rs-install-check");
+    match File::create(&probe) {
+        Ok(_) => {
+            let _ = fs::remove_file(&probe);
+        }
+        Err(ref e) if e.kind() == ErrorKind::PermissionDenied => {
+            return Err(Error::Io(io::Error::new(
+                e.kind(),
+                format!(
+                    "cannot install into {}: {}\n\
+                     check the permissions of the output directory, or use \
+                     `install_default_permissions` if the installed directories \
+                     aren't writable",
+                    dst.display(),
+                    e
+                ),
+            )));
+        }
+        Err(_) => {}
+    }
+    Ok(())
+}
+
+// Classifies a compiler flag from the `cc` crate, also returning whether the
+// flag's value is passed as the following argument.
+fn cc_flag_kind(arg: &OsStr) -> (CcFlagKinds, bool) {
+    let s = match arg.to_str() {
+        Some(s) => s,
+        None => return (CcFlagKinds::OTHER, false),
+    };
+    match s {
+        "-target" | "-arch" | "-isysroot" | "--sysroot" => (CcFlagKinds::TARGET, true),
+        _ if s.starts_with("--target=")
+            || s.starts_with("--sysroot=")
+            || s.starts_with("-m")
+            || s.starts_with("/arch:") =>
+        {
+            (CcFlagKinds::TARGET, false)
+        }
+        _ if (s.starts_with("-W") && !s.starts_with("-Wl,") && !s.starts_with("-Wa,"))
+            || s.starts_with("/W")
+            || s == "-w" =>
+        {
+            (CcFlagKinds::WARNINGS, false)
+        }
+        _ => (CcFlagKinds::OTHER, false),
+    }
+}
+
+fn format_duration(d: Duration) -> String {
+    format!("{}.{:02}s", d.as_secs(), d.subsec_millis() / 10)
+}
+
+// Returns whether `program` can be found in one of the directories of `PATH`.
+fn find_on_path(program: &str) -> bool {
+    let path = match env::var_os("PATH") {
+        Some(path) => path,
+        None => return false,
+    };
+    let names = if cfg!(windows) {
+        vec![format!("{}.exe", program), program.to_string()]
+    } else {
+        vec![program.to_string()]
+    };
+    env::split_paths(&path).any(|dir| names.iter().any(|name| dir.join(name).is_file()))
+}
+
+// Parses the contents of a pkg-config `.pc` file.
+fn parse_pc(contents: &str) -> PkgConfig {
+    let mut vars = HashMap::new();
+    let mut pc = PkgConfig::default();
+    for line in contents.replace("\\\n", " ").lines() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        let var = line.find('=');
+        let keyword = line.find(':');
+        match (var, keyword) {
+            (Some(i), k) if k.map_or(true, |k| i < k) => {
+                let value = expand_pc_vars(line[i + 1..].trim(), &vars);
+                vars.insert(line[..i].trim().to_string(), value);
+            }
+            (_, Some(i)) => {
+                let value = expand_pc_vars(line[i + 1..].trim(), &vars);
+                match line[..i].trim() {
+                    "Libs" => {
+                        for token in value.split_whitespace() {
+                            if let Some(dir) = token.strip_prefix("-L") {
+                                pc.link_paths.push(PathBuf::from(dir));
+                            } else if let Some(lib) = token.strip_prefix("-l") {
+                                pc.libs.push(lib.to_string());
+                            }
+                        }
+                    }
+                    "Cflags" => {
+                        for token in value.split_whitespace() {
+                            if let Some(dir) = token.strip_prefix("-I") {
+                                pc.include_paths.push(PathBuf::from(dir));
+                            } else if let Some(define) = token.strip_prefix("-D") {
+                                let mut parts = define.splitn(2, '=');
+                                let name = parts.next().unwrap_or("").to_string();
+                                pc.defines.push((name, parts.next().map(|v| v.to_string())));
+                            }
+                        }
+                    }
+                    "Requires" => {
+                        let mut skip_version = false;
+                        for token in value.split(|c: char| c == ',' || c.is_whitespace()) {
+                            if token.is_empty() {
+                                continue;
+                            }
+                            if skip_version {
+                                skip_version = false;
+                            } else if ["=", "<", ">", "<=", ">=", "!="].contains(&token) {
+                                skip_version = true;
+                            } else {
+                                pc.requires.push(token.to_string());
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    pc
+}
+
+// Substitutes the `${var}` references in a `.pc` file value.
+fn expand_pc_vars(value: &str, vars: &HashMap<String, String>) -> String {
+    let mut expanded = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        match rest[start..].find('}') {
+            Some(end) => {
+                let name = &rest[start + 2..start + end];
+                expanded.push_str(vars.get(name).map(|s| &s[..]).unwrap_or(""));
+                rest = &rest[start + end + 1..];
+            }
+            None => {
+                expanded.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+// Reads the `key = value` pairs of the table `table` from a TOML document,
+// converting the values to cmake strings. Errors carry the 1-based line number.
+fn parse_toml_defines(
+    contents: &str,
+    table: &str,
+) -> Result<Vec<(String, String)>, (usize, String)> {
+    let mut defines = Vec::new();
+    let mut in_table = false;
+    let mut found = false;
+    let mut lines = contents.lines().enumerate();
+    while let Some((i, line)) = lines.next() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with("[[") {
+            in_table = false;
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('[') {
+            let name = match header.find(']') {
+                Some(end) => &header[..end],
+                None => return Err((i + 1, "unterminated table header".to_string())),
+            };
+            let name = name
+                .split('.')
+                .map(|part| part.trim().trim_matches('"'))
+                .collect::<Vec<_>>()
+                .join(".");
+            in_table = name == table;
+            found |= in_table;
+            continue;
+        }
+        if !in_table {
+            continue;
+        }
+
+        let eq = match line.find('=') {
+            Some(eq) => eq,
+            None => return Err((i + 1, "expected `key = value`".to_string())),
+        };
+        let key = line[..eq].trim().trim_matches('"').to_string();
+        let mut value = line[eq + 1..].to_string();
+        loop {
+            match parse_toml_value(&value) {
+                Ok((v, rest)) => {
+                    let rest = rest.trim();
+                    if !rest.is_empty() && !rest.starts_with('#') {
+                        return Err((i + 1, format!("unexpected `{}` after value", rest)));
+                    }
+                    defines.push((key, v));
+                    break;
+                }
+                // An array may continue on the following lines.
+                Err(None) => match lines.next() {
+                    Some((_, next)) => {
+                        value.push('\n');
+                        value.push_str(next);
+                    }
+                    None => return Err((i + 1, "unterminated array".to_string())),
+                },
+                Err(Some(msg)) => return Err((i + 1, msg)),
+            }
+        }
+    }
+    if !found {
+        return Err((0, format!("no table `[{}]`", table)));
+    }
+    Ok(defines)
+}
+
+// Parses the TOML value at the start of `s` into a cmake string, returning the
+// remaining input. `Err(None)` means the input ended in the middle of the value.
+fn parse_toml_value(s: &str) -> Result<(String, &str), Option<String>> {
+    let s = s.trim_start();
+    if let Some(rest) = s.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Ok((value, &rest[i + 1..])),
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, 't')) => value.push('\t'),
+                    Some((_, 'r')) => value.push('\r'),
+                    Some((_, '"')) => value.push('"'),
+                    Some((_, '\\')) => value.push('\\'),
+                    _ => return Err(Some("unsupported escape in string".to_string())),
+                },
+                '\n' => break,
+                c => value.push(c),
+            }
+        }
+        Err(Some("unterminated string".to_string()))
+    } else if let Some(rest) = s.strip_prefix('\'') {
+        match rest.find(['\'', '\n']) {
+            Some(end) if rest[end..].starts_with('\'') => {
+                Ok((rest[..end].to_string(), &rest[end + 1..]))
+            }
+            _ => Err(Some("unterminated string".to_string())),
+        }
+    } else if let Some(mut rest) = s.strip_prefix('[') {
+        let mut elements = Vec::new();
+        loop {
+            rest = skip_toml_whitespace(rest);
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((elements.join(";"), after));
+            }
+            if rest.is_empty() {
+                return Err(None);
+            }
+            if rest.starts_with('[') {
+                return Err(Some("nested arrays are not supported".to_string()));
+            }
+            let (element, after) = parse_toml_value(rest)?;
+            elements.push(element.replace(';', "\\;"));
+            rest = skip_toml_whitespace(after);
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = after;
+            } else if !rest.starts_with(']') {
+                return if rest.is_empty() {
+                    Err(None)
+                } else {
+                    Err(Some("expected `,` or `]` in array".to_string()))
+                };
+            }
+        }
+    } else if s.starts_with('{') {
+        Err(Some("inline tables are not supported".to_string()))
+    } else {
+        let end = s
+            .find(|c: char| c.is_whitespace() || c == ',' || c == ']' || c == '#')
+            .unwrap_or(s.len());
+        let (token, rest) = s.split_at(end);
+        match token {
+            "true" => Ok(("ON".to_string(), rest)),
+            "false" => Ok(("OFF".to_string(), rest)),
+            "" => Err(Some("missing value".to_string())),
+            _ if token.starts_with(|c: char| c.is_ascii_digit() || c == '+' || c == '-')
+                || token == "inf"
+                || token == "nan" =>
+            {
+                Ok((token.replace('_', ""), rest))
+            }
+            _ => Err(Some(format!("invalid value `{}`", token))),
+        }
+    }
+}
+
+// Skips whitespace, newlines and comments between array elements.
+fn skip_toml_whitespace(mut s: &str) -> &str {
+    loop {
+        s = s.trim_start();
+        if s.starts_with('#') {
+            s = s.find('\n').map(|i| &s[i..]).unwrap_or("");
+        } else {
+            return s;
+        }
+    }
+}
+
+// The cache variables making cmake link with `linker`.
+fn linker_defines(linker: &Path, msvc: bool) -> Vec<(String, OsString)> {
+    let mut defines = Vec::new();
+    if msvc {
+        defines.push(("CMAKE_LINKER".to_string(), linker.as_os_str().to_owned()));
+        return defines;
+    }
+    let name = linker
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let fuse = if name.contains("lld") {
+        "lld"
+    } else if name.contains("mold") {
+        "mold"
+    } else if name.contains("gold") {
+        "gold"
+    } else {
+        // Most likely a compiler driver, which the linker isn't invoked as.
+        return defines;
+    };
+    defines.push(("CMAKE_LINKER".to_string(), linker.as_os_str().to_owned()));
+    for kind in &["EXE", "SHARED", "MODULE"] {
+        defines.push((
+            format!("CMAKE_{}_LINKER_FLAGS_INIT", kind),
+            format!("-fuse-ld={}", fuse).into(),
+        ));
+    }
+    defines
+}
+
+// Maps a Linux target triple to the Debian multiarch triple naming the arch
+// specific directories of a sysroot, e.g. `aarch64-linux-gnu`.
+fn multiarch_triple(target_triple: &str) -> Option<String> {
+    let parts = target_triple.split('-').collect::<Vec<_>>();
+    if parts.len() != 4 || parts[2] != "linux" {
+        return None;
+    }
+    let arch = match parts[0] {
+        "i586" | "i686" => "i386",
+        arch if arch.starts_with("arm") || arch.starts_with("thumb") => "arm",
+        arch => arch,
+    };
+    Some(format!("{}-linux-{}", arch, parts[3]))
+}
+
+// Maps the operating system of a target triple to the matching value of
+// `CMAKE_SYSTEM_NAME`.
+fn cmake_system_name(target_triple: &str) -> Option<&'static str> {
+    let names = [
+        ("android", "Android"),
+        ("linux", "Linux"),
+        ("windows", "Windows"),
+        ("darwin", "Darwin"),
+        ("ios", "iOS"),
+        ("tvos", "tvOS"),
+        ("watchos", "watchOS"),
+        ("freebsd", "FreeBSD"),
+        ("netbsd", "NetBSD"),
+        ("openbsd", "OpenBSD"),
+        ("dragonfly", "DragonFly"),
+        ("solaris", "SunOS"),
+        ("illumos", "SunOS"),
+        ("emscripten", "Emscripten"),
+        ("wasi", "WASI"),
+        ("none", "Generic"),
+    ];
+    names
+        .iter()
+        .find(|(os, _)| target_triple.split('-').any(|part| part.starts_with(os)))
+        .map(|&(_, name)| name)
+}
+
+// A hash of the program, arguments and environment changes of `cmd`, used to
+// detect whether an existing cmake cache was configured the same way.
+//
+// This is stored in the build directory, so it uses FNV-1a rather than the
+// standard library's hasher, whose output may change between Rust releases.
+fn command_hash(cmd: &Command, initial_cache: &str) -> String {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    let mut write = |s: &OsStr| {
+        for &b in s.to_string_lossy().as_bytes().iter().chain(&[0xff]) {
+            hash ^= u64::from(b);
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    };
+    write(OsStr::new(initial_cache));
+    write(cmd.get_program());
+    for arg in cmd.get_args() {
+        write(arg);
+    }
+    for (k, v) in cmd.get_envs() {
+        write(k);
+        // Distinguishes removed variables from ones set to "".
+        write(v.unwrap_or_else(|| OsStr::new("\u{0}")));
+    }
+    format!("{:016x}", hash)
+}
+
+// Finds the LTO aware variant of `tool` (`ar` or `ranlib`) belonging to the
+// given compiler, e.g. `aarch64-linux-gnu-gcc-ar` for `aarch64-linux-gnu-gcc`
+// or `llvm-ar-15` for `clang-15`.
+fn lto_tool(compiler: &Path, tool: &str) -> Option<PathBuf> {
+    let name = compiler.file_name()?.to_str()?;
+    let name = if let Some(pos) = name.find("clang") {
+        let rest = name[pos + "clang".len()..].trim_start_matches("++");
+        format!("llvm-{}{}", tool, rest)
+    } else {
+        let (pos, len) = match (name.find("gcc"), name.find("g++")) {
+            (Some(pos), _) => (pos, "gcc".len()),
+            (None, Some(pos)) => (pos, "g++".len()),
+            (None, None) => return None,
+        };
+        format!("{}gcc-{}{}", &name[..pos], tool, &name[pos + len..])
+    };
+    let exe = find_exe(compiler).with_file_name(&name);
+    if exe.is_file() {
+        return Some(exe);
+    }
+    let exe = find_exe(Path::new(&name));
+    if exe.is_file() {
+        Some(exe)
+    } else {
+        None
+    }
+}
+
+// Whether files can be created in `dir`, which is checked by creating one.
+fn is_writable_dir(dir: &Path) -> bool {
+    let probe = dir.join(".cmake-rs-write-probe");
+    match File::create(&probe) {
+        Ok(_) => {
+            let _ = fs::remove_file(&probe);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+// Whether the variable selects the toolchain or platform, which cmake needs to
+// know about before anything else on the first configure.
+fn is_toolchain_define(var: &str) -> bool {
+    let var = var.split(':').next().unwrap_or(var);
+    var == "CMAKE_TOOLCHAIN_FILE"
+        || var == "CMAKE_SYSROOT"
+        || var == "BUILD_SHARED_LIBS"
+        || var.starts_with("CMAKE_SYSTEM_")
+        || var.starts_with("CMAKE_OSX_")
+        || (var.starts_with("CMAKE_") && var.ends_with("_COMPILER"))
+}
+
+// Reads the files listed in the `install_manifest.txt` of a build directory,
+// which is empty if nothing was installed yet.
+fn read_install_manifest(build: &Path) -> Vec<PathBuf> {
+    fs::read_to_string(build.join("install_manifest.txt"))
+        .map(|manifest| {
+            manifest
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(PathBuf::from)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// The executable and library targets of a configured project, read from the
+// reply to a codemodel query of cmake's file API.
+fn file_api_targets(build: &Path) -> Vec<String> {
+    lazy_static! {
+        static ref TARGET_REGEX: Regex = Regex::new(
+            "\\{[^{}]*\"id\"\\s*:\\s*\"([^\"]+)::@[^{}]*\"jsonFile\"\\s*:\\s*\"([^\"]+)\"[^{}]*\\}"
+        )
+        .unwrap();
+        static ref TYPE_REGEX: Regex = Regex::new(
+            "\"type\"\\s*:\\s*\"(EXECUTABLE|STATIC_LIBRARY|SHARED_LIBRARY|MODULE_LIBRARY|OBJECT_LIBRARY)\""
+        )
+        .unwrap();
+    }
+
+    let reply = build.join(".cmake/api/v1/reply");
+    let codemodel = fs::read_dir(&reply)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with("codemodel-v2-")
+        })
+        .max_by_key(|entry| entry.metadata().and_then(|m| m.modified()).ok())
+        .and_then(|entry| fs::read_to_string(entry.path()).ok())
+        .unwrap_or_default();
 
-        if self.always_configure || !build.join("CMakeCache.txt").exists() {
-            run(cmd.env("CMAKE_PREFIX_PATH", cmake_prefix_path), "cmake");
-        } else {
-            println!("CMake project was already configured. Skipping configuration step.");
+    let mut targets = Vec::new();
+    for captures in TARGET_REGEX.captures_iter(&codemodel) {
+        let name = captures[1].to_string();
+        let buildable = fs::read_to_string(reply.join(&captures[2]))
+            .map(|json| TYPE_REGEX.is_match(&json))
+            .unwrap_or(false);
+        if buildable && !targets.contains(&name) {
+            targets.push(name);
         }
+    }
+    targets
+}
 
-        let mut makeflags = None;
-        let mut parallel_flags = None;
+// Quotes `s` as a JSON string, or `null` if it's unknown.
+fn json_string(s: Option<&str>) -> String {
+    let s = match s {
+        Some(s) => s,
+        None => return "null".to_string(),
+    };
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
 
-        if let Ok(s) = env::var("NUM_JOBS") {
-            match self.generator.as_ref().map(|g| g.to_string_lossy()) {
-                Some(ref g) if g.contains("Ninja") => {
-                    parallel_flags = Some(format!("-j{}", s));
-                }
-                Some(ref g) if g.contains("Visual Studio") => {
-                    parallel_flags = Some(format!("/m:{}", s));
-                }
-                Some(ref g) if g.contains("NMake") => {
-                    // NMake creates `Makefile`s, but doesn't understand `-jN`.
-                }
-                _ if fs::metadata(&build.join("Makefile")).is_ok() => {
-                    match env::var_os("CARGO_MAKEFLAGS") {
-                        // Only do this on non-windows and non-bsd
-                        // On Windows, we could be invoking make instead of
-                        // mingw32-make which doesn't work with our jobserver
-                        // bsdmake also does not work with our job server
-                        Some(ref s)
-                            if !(cfg!(windows)
-                                || cfg!(target_os = "openbsd")
-                                || cfg!(target_os = "netbsd")
-                                || cfg!(target_os = "freebsd")
-                                || cfg!(target_os = "bitrig")
-                                || cfg!(target_os = "dragonflybsd")) =>
-                        {
-                            makeflags = Some(s.clone())
+// A JSON value, as far as reading `CMakePresets.json` requires.
+enum Json {
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+    Other,
+}
+
+impl Json {
+    fn get(&self, key: &str) -> Option<&Json> {
+        match *self {
+            Json::Object(ref members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match *self {
+            Json::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+// Parses the JSON value at the start of `s`, returning the remaining input.
+fn parse_json(s: &str) -> Option<(Json, &str)> {
+    let s = s.trim_start();
+    if let Some(rest) = s.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Some((Json::String(value), &rest[i + 1..])),
+                '\\' => match chars.next()?.1 {
+                    'n' => value.push('\n'),
+                    't' => value.push('\t'),
+                    'r' => value.push('\r'),
+                    'b' => value.push('\u{8}'),
+                    'f' => value.push('\u{c}'),
+                    'u' => {
+                        let hex = rest.get(i + 2..i + 6)?;
+                        value.push(char::from_u32(u32::from_str_radix(hex, 16).ok()?)?);
+                        for _ in 0..4 {
+                            chars.next();
                         }
+                    }
+                    c => value.push(c),
+                },
+                c => value.push(c),
+            }
+        }
+        None
+    } else if let Some(mut rest) = s.strip_prefix('[') {
+        let mut elements = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                return Some((Json::Array(elements), after));
+            }
+            let (element, after) = parse_json(rest)?;
+            elements.push(element);
+            rest = after.trim_start();
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = after;
+            } else if !rest.starts_with(']') {
+                return None;
+            }
+        }
+    } else if let Some(mut rest) = s.strip_prefix('{') {
+        let mut members = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix('}') {
+                return Some((Json::Object(members), after));
+            }
+            let (key, after) = match parse_json(rest)? {
+                (Json::String(key), after) => (key, after),
+                _ => return None,
+            };
+            let (value, after) = parse_json(after.trim_start().strip_prefix(':')?)?;
+            members.push((key, value));
+            rest = after.trim_start();
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = after;
+            } else if !rest.starts_with('}') {
+                return None;
+            }
+        }
+    } else {
+        let end = s
+            .find(|c: char| c.is_whitespace() || c == ',' || c == ']' || c == '}')
+            .unwrap_or(s.len());
+        if end == 0 {
+            return None;
+        }
+        Some((Json::Other, &s[end..]))
+    }
+}
+
+// A build preset of the project, see `find_build_preset`.
+struct BuildPreset {
+    // The configuration the preset builds, if it selects one.
+    configuration: Option<String>,
+    // Whether its configure preset declares a `binaryDir`.
+    binary_dir: bool,
+}
+
+// Looks up the build preset `name` in the `CMakePresets.json` and
+// `CMakeUserPresets.json` files of the project in `source`, following the
+// `inherits` of the presets. Included preset files aren't read.
+fn find_build_preset(source: &Path, name: &str) -> Option<BuildPreset> {
+    let files = ["CMakePresets.json", "CMakeUserPresets.json"]
+        .iter()
+        .filter_map(|file| fs::read_to_string(source.join(file)).ok())
+        .filter_map(|contents| parse_json(&contents).map(|(json, _)| json))
+        .collect::<Vec<_>>();
+    let presets = |kind: &str| {
+        files
+            .iter()
+            .filter_map(|json| match json.get(kind) {
+                Some(Json::Array(presets)) => Some(presets),
+                _ => None,
+            })
+            .flatten()
+            .collect::<Vec<_>>()
+    };
+    let build_presets = presets("buildPresets");
+    let configure_presets = presets("configurePresets");
+    build_presets
+        .iter()
+        .find(|preset| preset.get("name").and_then(Json::as_str) == Some(name))?;
+
+    let string = |presets: &[&Json], name: &str, field: &str| {
+        preset_field(presets, name, field, 0)
+            .and_then(Json::as_str)
+            .map(String::from)
+    };
+    let configure_preset = string(&build_presets, name, "configurePreset");
+    Some(BuildPreset {
+        configuration: string(&build_presets, name, "configuration"),
+        binary_dir: configure_preset
+            .map(|c| preset_field(&configure_presets, &c, "binaryDir", 0).is_some())
+            .unwrap_or(false),
+    })
+}
+
+// The `field` of the preset `name` among `presets`, or of the first preset it
+// inherits from that sets it.
+fn preset_field<'a>(
+    presets: &[&'a Json],
+    name: &str,
+    field: &str,
+    depth: usize,
+) -> Option<&'a Json> {
+    // The presets can't inherit in a cycle, but don't hang on a broken file.
+    if depth > presets.len() {
+        return None;
+    }
+    let preset = presets
+        .iter()
+        .find(|preset| preset.get("name").and_then(Json::as_str) == Some(name))?;
+    if let Some(value) = preset.get(field) {
+        return Some(value);
+    }
+    let parents = match preset.get("inherits") {
+        Some(Json::String(parent)) => vec![parent.as_str()],
+        Some(Json::Array(parents)) => parents.iter().filter_map(Json::as_str).collect(),
+        _ => Vec::new(),
+    };
+    parents
+        .into_iter()
+        .find_map(|parent| preset_field(presets, parent, field, depth + 1))
+}
+
+// The `compilers` entry of the build report for `lang`, with the compiler id
+// and version cmake recorded in `CMakeFiles/<version>/CMake<lang>Compiler.cmake`.
+fn compiler_report(build: &Path, lang: &str, tool: &cc::Tool) -> String {
+    let file = format!("CMake{}Compiler.cmake", lang);
+    let contents = fs::read_dir(build.join("CMakeFiles"))
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .find_map(|entry| fs::read_to_string(entry.path().join(&file)).ok())
+        .unwrap_or_default();
+    let var = |name: &str| {
+        let prefix = format!("set(CMAKE_{}_COMPILER_{} \"", lang, name);
+        contents.lines().find_map(|line| {
+            let value = line.trim().strip_prefix(&prefix)?;
+            Some(
+                value
+                    .trim_end_matches(')')
+                    .trim_end_matches('"')
+                    .to_string(),
+            )
+        })
+    };
+    format!(
+        "{{\"path\": {}, \"id\": {}, \"version\": {}}}",
+        json_string(Some(&tool.path().to_string_lossy())),
+        json_string(var("ID").as_deref()),
+        json_string(var("VERSION").as_deref())
+    )
+}
+
+// The libraries installed into `dst/lib` and `dst/lib64`, as whether they are
+// shared along with the name to link them by, see `Config::build_libs`.
+fn installed_libs(dst: &Path) -> Vec<(bool, String)> {
+    let mut libs = Vec::new();
+    for dir in &["lib", "lib64"] {
+        let entries = match fs::read_dir(dst.join(dir)) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.filter_map(|e| e.ok()) {
+            if !entry.path().is_file() {
+                continue;
+            }
+            let file = entry.file_name().to_string_lossy().into_owned();
+            let lib = if let Some(name) = file.strip_suffix(".dll.a") {
+                Some((true, name.strip_prefix("lib").unwrap_or(name)))
+            } else if let Some(name) = file.strip_suffix(".lib") {
+                let dll = dst.join("bin").join(format!("{}.dll", name));
+                Some((dll.is_file(), name))
+            } else {
+                [(".a", false), (".so", true), (".dylib", true)]
+                    .iter()
+                    .find_map(|&(ext, shared)| {
+                        let name = file.strip_suffix(ext)?.strip_prefix("lib")?;
+                        Some((shared, name))
+                    })
+            };
+            if let Some((shared, name)) = lib {
+                if !name.is_empty() {
+                    libs.push((shared, name.to_string()));
+                }
+            }
+        }
+    }
+    libs.sort();
+    libs.dedup();
+    libs
+}
+
+// Whether `defines` are numerous or long enough for `defines_via_cache_file`
+// to be turned on automatically.
+fn exceeds_define_limits(defines: &[OsString]) -> bool {
+    defines.len() > 100 || defines.iter().map(|d| d.len()).sum::<usize>() > 8192
+}
+
+// The kind of generator the project is configured with, see
+// `native_tool_arg_for`.
+fn generator_kind(generator: Option<&OsStr>, target_triple: &str) -> Option<GeneratorKind> {
+    match generator {
+        Some(generator) => GeneratorKind::of(&generator.to_string_lossy()),
+        // MSVC builds always get an explicit Visual Studio generator.
+        None if target_triple.contains("msvc") => Some(GeneratorKind::VisualStudio),
+        None => Some(GeneratorKind::Make),
+    }
+}
+
+// Splits `s` into arguments at whitespace outside of quotes. A backslash only
+// escapes a following quote, backslash or whitespace character, so Windows
+// paths pass through unchanged.
+fn split_args(s: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut in_arg = false;
+    let mut quote = None;
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            ('\\', q) if q != Some('\'') => match chars.peek() {
+                Some(&next)
+                    if next == '"'
+                        || next == '\\'
+                        || (q.is_none() && (next == '\'' || next.is_whitespace())) =>
+                {
+                    arg.push(next);
+                    chars.next();
+                }
+                _ => arg.push(c),
+            },
+            (c, Some(q)) if c == q => quote = None,
+            (c, Some(_)) => arg.push(c),
+            ('"', None) | ('\'', None) => quote = Some(c),
+            (c, None) if c.is_whitespace() => {
+                if in_arg {
+                    args.push(std::mem::take(&mut arg));
+                }
+                in_arg = false;
+                continue;
+            }
+            (c, None) => arg.push(c),
+        }
+        in_arg = true;
+    }
+    if in_arg {
+        args.push(arg);
+    }
+    args
+}
+
+// The Visual Studio generator to build `target` with the given version of
+// Visual Studio. ARM64 targets require Visual Studio 2019 or newer.
+fn visual_studio_generator_for(
+    version: cc::windows_registry::VsVers,
+    target: &str,
+) -> Result<&'static str, String> {
+    use cc::windows_registry::VsVers;
+
+    // The architecture is passed with `-A` rather than as a ` Win64` style
+    // suffix, which the 2019 and newer generators reject.
+    let base = match version {
+        VsVers::Vs18 => "Visual Studio 18 2026",
+        VsVers::Vs17 => "Visual Studio 17 2022",
+        VsVers::Vs16 => "Visual Studio 16 2019",
+        VsVers::Vs15 => "Visual Studio 15 2017",
+        VsVers::Vs14 => "Visual Studio 14 2015",
+        _ => {
+            return Err("Visual studio version detected but this crate \
+                        doesn't know how to generate cmake files for it, \
+                        can the `cmake` crate be updated?"
+                .to_string())
+        }
+    };
+    // The 2017 generator only gained ARM64 support late in its life, so
+    // ARM64 builds need 2019 or newer.
+    if target.contains("aarch64") && matches!(version, VsVers::Vs14 | VsVers::Vs15) {
+        return Err(format!(
+            "building for {}: ARM64 requires VS2019 or newer, but only {} was found",
+            target, base
+        ));
+    }
+    if msvc_platform(target).is_some() {
+        Ok(base)
+    } else {
+        Err(format!("unsupported msvc target: {}", target))
+    }
+}
+
+// The platform Visual Studio generators take with `-A` for `target`.
+fn msvc_platform(target: &str) -> Option<&'static str> {
+    if target.contains("x86_64") {
+        Some("x64")
+    } else if target.contains("thumbv7a") {
+        Some("arm")
+    } else if target.contains("aarch64") {
+        Some("ARM64")
+    } else if target.contains("i686") {
+        Some("Win32")
+    } else {
+        None
+    }
+}
+
+// The generator selected with `-G` among `args`, the last one winning.
+fn generator_arg(args: &[OsString]) -> Option<OsString> {
+    let mut generator = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let arg = arg.to_string_lossy();
+        if arg == "-G" {
+            generator = args.next().cloned();
+        } else if let Some(name) = arg.strip_prefix("-G") {
+            generator = Some(name.into());
+        }
+    }
+    generator
+}
+
+// Reads the prefix directories listed in `file`, one per line.
+fn read_prefix_path_file(file: &Path) -> Result<Vec<PathBuf>, Error> {
+    let contents =
+        fs::read_to_string(file).map_err(|e| io_context("read prefix path file", file, e))?;
+    let base = file.parent().unwrap_or_else(|| Path::new(""));
+    Ok(contents
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| base.join(line))
+        .collect())
+}
+
+fn is_shared_lib(name: &str) -> bool {
+    name.ends_with(".so")
+        || name.contains(".so.")
+        || name.ends_with(".dylib")
+        || name.ends_with(".dll")
+}
+
+// Copies the shared libraries installed under `prefix` into `dir`.
+fn copy_shared_libs(prefix: &Path, dir: &Path) -> Result<(), Error> {
+    fs::create_dir_all(dir).map_err(|e| io_context("create", dir, e))?;
+    for subdir in &["lib", "lib64", "bin"] {
+        let entries = match fs::read_dir(prefix.join(subdir)) {
+            Ok(entries) => entries,
+            Err(..) => continue,
+        };
+        for entry in entries.filter_map(|e| e.ok()) {
+            let name = entry.file_name();
+            if !name.to_str().is_some_and(is_shared_lib) {
+                continue;
+            }
+            let src = entry.path();
+            let dst = dir.join(&name);
+            if let Err(e) = copy_preserving_symlinks(&src, &dst) {
+                return Err(Error::Io(io::Error::new(
+                    e.kind(),
+                    format!(
+                        "failed to copy {} to {}: {}",
+                        src.display(),
+                        dst.display(),
+                        e
+                    ),
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn copy_preserving_symlinks(src: &Path, dst: &Path) -> std::io::Result<()> {
+    if fs::symlink_metadata(dst).is_ok() {
+        fs::remove_file(dst)?;
+    }
+    if fs::symlink_metadata(src)?.file_type().is_symlink() {
+        std::os::unix::fs::symlink(fs::read_link(src)?, dst)
+    } else {
+        fs::copy(src, dst).map(|_| ())
+    }
+}
+
+#[cfg(not(unix))]
+fn copy_preserving_symlinks(src: &Path, dst: &Path) -> std::io::Result<()> {
+    fs::copy(src, dst).map(|_| ())
+}
+
+// Makes `dst` a copy of the tree at `src`, only copying files whose size or
+// modification time changed. Files that only exist in `dst` are kept, as they
+// are most likely generated by the project. `skip` and its parents are left
+// out of the copy, since the output directory may well be inside the source
+// tree.
+fn sync_tree(src: &Path, dst: &Path, skip: &Path) -> io::Result<()> {
+    fs::create_dir_all(dst)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let path = entry.path();
+        if skip.starts_with(&path) || entry.file_name() == ".git" {
+            continue;
+        }
+        let target = dst.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            sync_tree(&path, &target, skip)?;
+        } else if file_type.is_symlink() {
+            let changed = match (fs::read_link(&path), fs::read_link(&target)) {
+                (Ok(a), Ok(b)) => a != b,
+                _ => true,
+            };
+            if changed {
+                copy_preserving_symlinks(&path, &target)?;
+            }
+        } else {
+            let meta = entry.metadata()?;
+            let up_to_date = match fs::symlink_metadata(&target) {
+                Ok(old) => {
+                    old.is_file()
+                        && old.len() == meta.len()
+                        && old.modified().ok() == meta.modified().ok()
+                }
+                Err(_) => false,
+            };
+            if !up_to_date {
+                if let Ok(old) = fs::symlink_metadata(&target) {
+                    if old.is_dir() {
+                        fs::remove_dir_all(&target)?;
+                    }
+                }
+                copy_preserving_symlinks(&path, &target)?;
+                make_writable(&target)?;
+                if let Ok(modified) = meta.modified() {
+                    File::options()
+                        .write(true)
+                        .open(&target)?
+                        .set_modified(modified)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Sources may be read-only, but the copy is there to be written to.
+#[cfg(unix)]
+fn make_writable(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut perms = fs::metadata(path)?.permissions();
+    perms.set_mode(perms.mode() | 0o200);
+    fs::set_permissions(path, perms)
+}
+
+#[cfg(not(unix))]
+fn make_writable(path: &Path) -> io::Result<()> {
+    let mut perms = fs::metadata(path)?.permissions();
+    #[allow(clippy::permissions_set_readonly_false)]
+    perms.set_readonly(false);
+    fs::set_permissions(path, perms)
+}
+
+// Runs `cmd` as the given step of the build. When `capture` is set the output
+// is captured, returned, and replayed if the command fails. If `timeout`
+// expires the command is killed along with everything it spawned.
+fn run(
+    cmd: &mut Command,
+    program: &str,
+    step: Step,
+    quiet: bool,
+    capture: bool,
+    timeout: Option<Duration>,
+) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    if capture {
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+    }
+    if !quiet {
+        println!("running: {:?}", cmd);
+    }
+    if timeout.is_some() {
+        set_process_group(cmd);
+    }
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => {
+            return Err(Error::CommandNotFound {
+                program: program.to_string(),
+            });
+        }
+        Err(e) => {
+            return Err(Error::Io(io::Error::new(
+                e.kind(),
+                format!("failed to execute command: {}", e),
+            )))
+        }
+    };
+
+    // The pipes are drained on separate threads so that a chatty command
+    // can't block on a full pipe.
+    let stdout = child.stdout.take().map(|mut out| {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            let _ = out.read_to_end(&mut buf);
+            buf
+        })
+    });
+    let stderr = child.stderr.take().map(|mut err| {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            let _ = err.read_to_end(&mut buf);
+            buf
+        })
+    });
+
+    let status = match timeout {
+        Some(timeout) => wait_timeout(&mut child, timeout).map_err(Error::Io)?,
+        None => Some(child.wait().map_err(Error::Io)?),
+    };
+    if status.is_none() {
+        kill_process_tree(&mut child);
+    }
+    let stdout = stdout.and_then(|t| t.join().ok()).unwrap_or_default();
+    let stderr = stderr.and_then(|t| t.join().ok()).unwrap_or_default();
+
+    let failed = status.map(|status| !status.success()).unwrap_or(true);
+    if capture && failed {
+        if quiet {
+            println!("running: {:?}", cmd);
+        }
+        let _ = io::stdout().write_all(&stdout);
+        let _ = io::stderr().write_all(&stderr);
+    }
+    match status {
+        None => Err(Error::Timeout { phase: step }),
+        Some(status) if !status.success() => Err(Error::CommandFailed {
+            step,
+            status,
+            stderr: output_tail(&stderr, 20),
+        }),
+        Some(_) => Ok((stdout, stderr)),
+    }
+}
+
+// The warnings in cmake's output, each joined into a single line. A warning
+// starts with a line like `CMake Warning at CMakeLists.txt:3 (message):` and
+// its message is indented on the lines that follow.
+fn cmake_warnings(output: &str) -> Vec<String> {
+    let mut warnings = Vec::new();
+    let mut lines = output.lines().peekable();
+    while let Some(line) = lines.next() {
+        if !(line.starts_with("CMake Warning") || line.starts_with("CMake Deprecation Warning")) {
+            continue;
+        }
+        let mut warning = line.trim_end_matches(':').to_string();
+        while let Some(next) = lines.peek() {
+            if !next.is_empty() && !next.starts_with(char::is_whitespace) {
+                break;
+            }
+            if !next.trim().is_empty() {
+                warning.push(' ');
+                warning.push_str(next.trim());
+            }
+            lines.next();
+        }
+        warnings.push(warning);
+    }
+    warnings
+}
+
+// The last `lines` lines of captured output.
+fn output_tail(output: &[u8], lines: usize) -> String {
+    let output = String::from_utf8_lossy(output);
+    let all = output.trim_end().lines().collect::<Vec<_>>();
+    all[all.len().saturating_sub(lines)..].join("\n")
+}
+
+// Waits for `child` to exit for at most `timeout`, returning `None` if it's
+// still running by then.
+fn wait_timeout(child: &mut Child, timeout: Duration) -> io::Result<Option<ExitStatus>> {
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if start.elapsed() >= timeout {
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+// Puts the command in its own process group so that it can be killed along
+// with the processes it spawns (compilers started by make or ninja).
+#[cfg(unix)]
+fn set_process_group(cmd: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    cmd.process_group(0);
+}
+
+#[cfg(not(unix))]
+fn set_process_group(_cmd: &mut Command) {}
+
+// Kills `child` and its descendants, giving them a moment to exit cleanly
+// first on Unix.
+fn kill_process_tree(child: &mut Child) {
+    let pid = child.id().to_string();
+    if cfg!(windows) {
+        let _ = Command::new("taskkill")
+            .args(["/T", "/F", "/PID", &pid])
+            .output();
+    } else {
+        let group = format!("-{}", pid);
+        let _ = Command::new("kill").args(["-TERM", "--", &group]).output();
+        let _ = wait_timeout(child, Duration::from_secs(2));
+        // Whatever is left of the group, e.g. processes ignoring SIGTERM.
+        let _ = Command::new("kill").args(["-KILL", "--", &group]).output();
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+// A `(major, minor, patch)` cmake version.
+type CMakeVersion = (u32, u32, u32);
+
+// Determines the version of the given cmake executable by parsing the output of
+// `cmake --version`. The result is cached since several features need to know
+// which version they're talking to.
+fn executable_version(executable: &OsStr) -> Option<CMakeVersion> {
+    lazy_static! {
+        static ref VERSIONS: Mutex<HashMap<OsString, Option<CMakeVersion>>> =
+            Mutex::new(HashMap::new());
+        static ref VERSION_REGEX: Regex =
+            Regex::new("version (\\d+)\\.(\\d+)(?:\\.(\\d+))?").unwrap();
+    }
+
+    let mut versions = VERSIONS.lock().unwrap();
+    if let Some(version) = versions.get(executable) {
+        return *version;
+    }
+    let version = Command::new(executable)
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|output| {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let captures = VERSION_REGEX.captures(&stdout)?;
+            let part = |i| {
+                captures
+                    .get(i)
+                    .map_or(Some(0), |m: regex::Match| m.as_str().parse().ok())
+            };
+            Some((part(1)?, part(2)?, part(3)?))
+        });
+    versions.insert(executable.to_owned(), version);
+    version
+}
+
+// Reads the minimum version from the `cmake_minimum_required` call of the
+// project's top-level `CMakeLists.txt`. For a `min...max` range only the
+// minimum is required to be installed.
+fn project_min_cmake_version(source_dir: &Path) -> Option<CMakeVersion> {
+    lazy_static! {
+        static ref MIN_REQUIRED_REGEX: Regex = Regex::new(
+            "(?i)cmake_minimum_required\\s*\\(\\s*VERSION\\s+(\\d+)\\.(\\d+)(?:\\.(\\d+))?"
+        )
+        .unwrap();
+    }
+
+    let contents = fs::read_to_string(source_dir.join("CMakeLists.txt")).ok()?;
+    let code = contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or(""))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let captures = MIN_REQUIRED_REGEX.captures(&code)?;
+    let part = |i| {
+        captures
+            .get(i)
+            .map_or(Some(0), |m: regex::Match| m.as_str().parse().ok())
+    };
+    Some((part(1)?, part(2)?, part(3)?))
+}
+
+// Asks `xcrun` where the given SDK lives, returning `None` if `xcrun` isn't
+// installed (e.g. when cross compiling from a non-Apple host) or doesn't know
+// about the SDK, in which case cmake is left to resolve the name itself.
+fn xcrun_sdk_path(sdk: &str) -> Option<PathBuf> {
+    let output = match Command::new("xcrun")
+        .arg("--sdk")
+        .arg(sdk)
+        .arg("--show-sdk-path")
+        .output()
+    {
+        Ok(output) => output,
+        Err(..) => return None,
+    };
+    if !output.status.success() {
+        eprintln!(
+            "Warning: xcrun could not find the path of the {} SDK: {}",
+            sdk,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        return None;
+    }
+    let path = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if path.is_empty() {
+        None
+    } else {
+        Some(PathBuf::from(path))
+    }
+}
+
+fn find_exe(path: &Path) -> PathBuf {
+    env::split_paths(&env::var_os("PATH").unwrap_or_default())
+        .map(|p| p.join(path))
+        .find(|p| fs::metadata(p).is_ok())
+        .unwrap_or(path.to_owned())
+}
+
+// Wraps an I/O error with the path it happened on, e.g. "failed to create
+// /path: permission denied".
+fn io_context(action: &str, path: &Path, e: io::Error) -> Error {
+    Error::Io(io::Error::new(
+        e.kind(),
+        format!("failed to {} {}: {}", action, path.display(), e),
+    ))
+}
+
+fn fail(s: &str) -> ! {
+    panic!("\n{}\n\nbuild script failed, must exit now", s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A scratch directory holding a project and a stand-in for cmake that
+    // logs its arguments, so that the configure step can run without cmake.
+    // It reports version 3.20.1 unless a `version` file says otherwise.
+    #[cfg(unix)]
+    fn fake_project(name: &str) -> (PathBuf, Config) {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = env::temp_dir().join(format!("cmake-rs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("src/CMakeLists.txt"), "project(fake C)\n").unwrap();
+        let cmake = dir.join("cmake");
+        fs::write(
+            &cmake,
+            "#!/bin/sh\n\
+             dir=$(dirname \"$0\")\n\
+             if [ \"$1\" = --version ]; then\n\
+                 echo \"cmake version $(cat \"$dir/version\" 2>/dev/null || echo 3.20.1)\"\n\
+                 exit 0\n\
+             fi\n\
+             {\n\
+                 for arg in \"$@\"; do echo \"$arg\"; done\n\
+                 [ -z \"$CMAKE_RS_TEST_VAR\" ] || echo \"env: $CMAKE_RS_TEST_VAR\"\n\
+                 echo ---\n\
+             } >> \"$dir/args\"\n",
+        )
+        .unwrap();
+        fs::set_permissions(&cmake, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut config = Config::new(dir.join("src"));
+        config
+            .target("x86_64-unknown-linux-gnu")
+            .host("x86_64-unknown-linux-gnu")
+            .out_dir(dir.join("out"))
+            .profile("Release");
+        config
+            .env_cache
+            .lock()
+            .unwrap()
+            .insert("CMAKE".to_string(), Some(cmake.into()));
+        (dir, config)
+    }
+
+    // The arguments of each cmake invocation of a `fake_project`, other than
+    // `--version`.
+    #[cfg(unix)]
+    fn cmake_runs(dir: &Path) -> Vec<Vec<String>> {
+        let log = fs::read_to_string(dir.join("args")).unwrap();
+        log.split_terminator("---\n")
+            .map(|run| run.lines().map(String::from).collect())
+            .collect()
+    }
+
+    #[test]
+    fn config_is_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Config>();
+    }
+
+    #[test]
+    fn command_hash_is_fnv() {
+        // Stored in build directories, so it must not change between builds
+        // of this crate.
+        let mut cmd = Command::new("cmake");
+        cmd.arg("-DA=1");
+        assert_eq!(command_hash(&cmd, ""), "1dd33b247563ca41");
+        cmd.env("CC", "");
+        let set = command_hash(&cmd, "");
+        cmd.env_remove("CC");
+        assert_ne!(command_hash(&cmd, ""), set);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn typed_make_program_define() {
+        let (dir, mut config) = fake_project("typed-make-program");
+        config.define("CMAKE_MAKE_PROGRAM:FILEPATH", dir.join("missing-make"));
+        match config.try_build() {
+            Err(Error::Config(msg)) => assert!(msg.contains("missing-make"), "{}", msg),
+            other => panic!("unexpected result {:?}", other),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn generator_from_env_is_not_stored() {
+        let (dir, mut config) = fake_project("generator-env");
+        config
+            .env_cache
+            .lock()
+            .unwrap()
+            .insert("CMAKE_GENERATOR".to_string(), Some("Unix Makefiles".into()));
+        config.try_configure().unwrap();
+
+        let args = fs::read_to_string(dir.join("args")).unwrap();
+        assert!(args.contains("\n-G\nUnix Makefiles\n"), "{}", args);
+        assert_eq!(config.generator, None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn generator_from_args() {
+        let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+        assert_eq!(generator_arg(&args(&["-Wno-dev"])), None);
+        assert_eq!(
+            generator_arg(&args(&["-G", "Unix Makefiles"])),
+            Some("Unix Makefiles".into())
+        );
+        assert_eq!(
+            generator_arg(&args(&["-GNinja", "-G", "Xcode", "-DA=1"])),
+            Some("Xcode".into())
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn generator_from_configure_arg_is_checked() {
+        let (dir, mut config) = fake_project("generator-arg");
+        config
+            .generator("Unix Makefiles")
+            .configure_arg("-GNMake Makefiles JOM");
+        match config.try_configure() {
+            Err(Error::Config(msg)) => assert!(msg.contains("requires jom"), "{}", msg),
+            other => panic!("unexpected result {:?}", other),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cmake_args_splitting() {
+        assert_eq!(split_args(""), Vec::<String>::new());
+        assert_eq!(split_args("  -DA=1\t-DB=2 \n"), ["-DA=1", "-DB=2"]);
+        assert_eq!(
+            split_args(r#"-DFLAGS="-O2 -g" '-DNAME=it''s' -DX="""#),
+            ["-DFLAGS=-O2 -g", "-DNAME=its", "-DX="]
+        );
+        assert_eq!(
+            split_args(r#"-DMSG=say\ \"hi\" "-DQ=a \"b\" c" '-DS=\n'"#),
+            ["-DMSG=say \"hi\"", "-DQ=a \"b\" c", "-DS=\\n"]
+        );
+        assert_eq!(
+            split_args(r"-DCMAKE_PREFIX_PATH=C:\deps\lib -G Ninja"),
+            [r"-DCMAKE_PREFIX_PATH=C:\deps\lib", "-G", "Ninja"]
+        );
+        assert_eq!(split_args("'' \"\""), ["", ""]);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn build_configs_start_from_the_same_settings() {
+        let (dir, mut config) = fake_project("build-configs");
+        config.define_with("REVISION", || "abc");
+        let prefixes = config.build_configs(&["Debug", "MinSizeRel"]);
+
+        let out = dir.join("out");
+        assert_eq!(prefixes, [out.join("Debug"), out.join("MinSizeRel")]);
+        assert_eq!(config.out_dir, Some(out));
+        assert_eq!(config.profile.as_deref(), Some("Release"));
+        let args = fs::read_to_string(dir.join("args")).unwrap();
+        assert_eq!(args.matches("-DREVISION=abc\n").count(), 2, "{}", args);
+        assert_eq!(args.matches("-DCMAKE_BUILD_TYPE=Debug\n").count(), 1);
+        assert_eq!(args.matches("-DCMAKE_BUILD_TYPE=MinSizeRel\n").count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn clean_uses_build_dir_name() {
+        let dir = env::temp_dir().join(format!("cmake-rs-clean-{}", std::process::id()));
+        fs::create_dir_all(dir.join("build")).unwrap();
+        fs::create_dir_all(dir.join("build-debug/CMakeFiles")).unwrap();
+        let mut config = Config::new(&dir);
+        config.out_dir(&dir).build_dir_name("build-debug");
+        assert_eq!(
+            config.cache_file_path(),
+            dir.join("build-debug/CMakeCache.txt")
+        );
+
+        config.clean().unwrap();
+        assert!(!dir.join("build-debug").exists());
+        assert!(dir.join("build").exists());
+        config.clean().unwrap();
+        clean(&dir).unwrap();
+        assert!(!dir.join("build").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn repeated_defines_keep_the_last_value() {
+        let (dir, mut config) = fake_project("dedup");
+        config
+            .define("FOO", "1")
+            .define("BAR", "x")
+            .define("FOO", "2")
+            .define_typed("FOO", "STRING", "3")
+            .define("BAZ", "y");
+        config.try_configure().unwrap();
+
+        let configure = &cmake_runs(&dir)[0];
+        let foo = configure
+            .iter()
+            .filter(|arg| arg.starts_with("-DFOO"))
+            .collect::<Vec<_>>();
+        assert_eq!(foo, ["-DFOO:STRING=3"]);
+        let pos = |arg: &str| configure.iter().position(|a| a == arg).unwrap();
+        assert!(pos("-DBAR=x") < pos("-DFOO:STRING=3"));
+        assert!(pos("-DFOO:STRING=3") < pos("-DBAZ=y"));
+        let metadata = config.metadata.lock().unwrap();
+        assert!(metadata
+            .iter()
+            .any(|line| line.starts_with("cargo:warning=cmake variable `FOO")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn list_defines_are_single_arguments() {
+        let (dir, mut config) = fake_project("define-list");
+        config
+            .define_list("SOURCES", ["a.c", "dir with spaces/b.c", "c;d.c"])
+            .define("NAME", "two words");
+        config.try_configure().unwrap();
+
+        let configure = &cmake_runs(&dir)[0];
+        assert!(configure.contains(&r"-DSOURCES=a.c;dir with spaces/b.c;c\;d.c".to_string()));
+        assert!(configure.contains(&"-DNAME=two words".to_string()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn missing_out_dir_is_created() {
+        let (dir, mut config) = fake_project("deep-out-dir");
+        let out = dir.join("a/b/c/out");
+        config.out_dir(&out);
+        assert_eq!(config.try_build().unwrap(), out);
+        assert!(out.join("build").is_dir());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "can't contain `=`")]
+    fn define_key_with_equals_is_rejected() {
+        Config::new("foo").define("FOO=BAR", "1");
+    }
+
+    #[test]
+    #[should_panic(expected = "must not be empty")]
+    fn empty_define_key_is_rejected() {
+        Config::new("foo").define("", "1");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn define_value_with_equals_is_kept() {
+        let (dir, mut config) = fake_project("define-equals");
+        config
+            .define("OPTS", "a=b=c")
+            .define_typed("EMPTY", "STRING", "");
+        config.try_configure().unwrap();
+
+        let configure = &cmake_runs(&dir)[0];
+        assert!(configure.contains(&"-DOPTS=a=b=c".to_string()));
+        assert!(configure.contains(&"-DEMPTY:STRING=".to_string()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn explicit_generator_wins_over_env() {
+        let (dir, mut config) = fake_project("generator-precedence");
+        config
+            .env_cache
+            .lock()
+            .unwrap()
+            .insert("CMAKE_GENERATOR".to_string(), Some("Ninja".into()));
+        config.generator("Unix Makefiles");
+        config.try_configure().unwrap();
+
+        let configure = &cmake_runs(&dir)[0];
+        let g = configure.iter().position(|arg| arg == "-G").unwrap();
+        assert_eq!(configure[g + 1], "Unix Makefiles");
+        assert_eq!(configure.iter().filter(|arg| *arg == "-G").count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn toolchain_file_suppresses_compilers() {
+        let (dir, mut config) = fake_project("toolchain-file");
+        let toolchain = dir.join("toolchain.cmake");
+        fs::write(&toolchain, "set(CMAKE_C_COMPILER clang)\n").unwrap();
+        config.define_toolchain_file(&toolchain);
+        config.try_configure().unwrap();
+
+        let configure = &cmake_runs(&dir)[0];
+        let arg = format!("-DCMAKE_TOOLCHAIN_FILE={}", toolchain.display());
+        assert!(configure.contains(&arg), "{:?}", configure);
+        assert!(!configure.iter().any(|arg| arg.contains("_COMPILER=")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn env_reaches_every_cmake_run() {
+        let (dir, mut config) = fake_project("env");
+        config.env("CMAKE_RS_TEST_VAR", "from config");
+        config.try_build().unwrap();
 
-                        // This looks like `make`, let's hope it understands `-jN`.
-                        _ => makeflags = Some(OsString::from(format!("-j{}", s))),
-                    }
-                }
-                _ => {}
-            }
+        let runs = cmake_runs(&dir);
+        assert!(runs.len() >= 2);
+        for run in &runs {
+            assert_eq!(run.last().unwrap(), "env: from config", "{:?}", run);
         }
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
-        // And build!
-        let cmake_target = self.cmake_target.clone().unwrap_or("install".to_string());
-        let mut cmd = Command::new(&executable);
-        for &(ref k, ref v) in c_compiler.env().iter().chain(&self.env) {
-            if target.should_exclude_env_var(k, v) {
-                continue;
-            }
-            cmd.env(k, v);
-        }
+    #[test]
+    #[cfg(unix)]
+    fn build_target_replaces_install() {
+        let (dir, mut config) = fake_project("build-target");
+        config.build_target("mylib");
+        config.try_build().unwrap();
 
-        if let Some(flags) = makeflags {
-            cmd.env("MAKEFLAGS", flags);
-        }
+        let runs = cmake_runs(&dir);
+        assert_eq!(runs.len(), 2, "{:?}", runs);
+        assert!(runs[1].windows(2).any(|w| w == ["--target", "mylib"]));
+        assert!(!runs.iter().flatten().any(|arg| arg == "--install"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
-        cmd.arg("--build").arg(".");
+    #[test]
+    fn aarch64_msvc_generator() {
+        use cc::windows_registry::VsVers;
 
-        if !self.no_build_target {
-            cmd.arg("--target").arg(cmake_target);
+        let target = "aarch64-pc-windows-msvc";
+        assert_eq!(msvc_platform(target), Some("ARM64"));
+        assert_eq!(msvc_platform("x86_64-pc-windows-msvc"), Some("x64"));
+        assert_eq!(msvc_platform("riscv64-pc-windows-msvc"), None);
+        assert_eq!(
+            visual_studio_generator_for(VsVers::Vs17, target),
+            Ok("Visual Studio 17 2022")
+        );
+        assert_eq!(
+            visual_studio_generator_for(VsVers::Vs16, target),
+            Ok("Visual Studio 16 2019")
+        );
+        for old in [VsVers::Vs14, VsVers::Vs15] {
+            let err = visual_studio_generator_for(old, target).unwrap_err();
+            assert!(err.contains("ARM64 requires VS2019 or newer"), "{}", err);
         }
+        assert_eq!(
+            visual_studio_generator_for(VsVers::Vs15, "x86_64-pc-windows-msvc"),
+            Ok("Visual Studio 15 2017")
+        );
+        assert!(visual_studio_generator_for(VsVers::Vs17, "riscv64-pc-windows-msvc").is_err());
+    }
 
-        cmd.arg("--config")
-            .arg(&profile)
-            .arg("--")
-            .args(&self.build_args)
-            .current_dir(&build);
+    #[test]
+    #[cfg(unix)]
+    fn very_verbose_flags() {
+        let (dir, mut config) = fake_project("very-verbose");
+        config.very_verbose(true);
+        config.try_build().unwrap();
 
-        if let Some(flags) = parallel_flags {
-            cmd.arg(flags);
-        }
+        let runs = cmake_runs(&dir);
+        assert!(runs[0].contains(&"-DCMAKE_VERBOSE_MAKEFILE:BOOL=ON".to_string()));
+        assert!(runs[1].contains(&"--verbose".to_string()));
+        assert!(!runs[1].contains(&"VERBOSE=1".to_string()));
+        fs::remove_dir_all(&dir).unwrap();
 
-        run(&mut cmd, "cmake");
+        let (dir, mut config) = fake_project("very-verbose-old");
+        fs::write(dir.join("version"), "3.13.4").unwrap();
+        config.very_verbose(true);
+        config.try_build().unwrap();
 
-        println!("cargo:root={}", dst.display());
-        return dst;
+        let build = cmake_runs(&dir)
+            .into_iter()
+            .find(|run| run[0] == "--build")
+            .unwrap();
+        assert!(!build.contains(&"--verbose".to_string()));
+        let separator = build.iter().position(|arg| arg == "--").unwrap();
+        assert!(build[separator..].contains(&"VERBOSE=1".to_string()));
+        fs::remove_dir_all(&dir).unwrap();
     }
 
-    fn visual_studio_generator(&self, target: &str) -> String {
-        use cc::windows_registry::{find_vs_version, VsVers};
-
-        let base = match find_vs_version() {
-            Ok(VsVers::Vs16) => "Visual Studio 16 2019",
-            Ok(VsVers::Vs15) => "Visual Studio 15 2017",
-            Ok(VsVers::Vs14) => "Visual Studio 14 2015",
-            Ok(VsVers::Vs12) => "Visual Studio 12 2013",
-            Ok(_) => panic!(
-                "Visual studio version detected but this crate \
-                 doesn't know how to generate cmake files for it, \
-                 can the `cmake` crate be updated?"
-            ),
-            Err(msg) => panic!(msg),
+    #[test]
+    #[cfg(unix)]
+    fn system_name_for_another_os() {
+        let system_name = |target: &str, define: Option<&str>| {
+            let (dir, mut config) = fake_project(&format!("system-name-{}", target));
+            config.target(target);
+            if let Some(name) = define {
+                config.define("CMAKE_SYSTEM_NAME", name);
+            }
+            config.try_configure().unwrap();
+            let names = cmake_runs(&dir)[0]
+                .iter()
+                .filter_map(|arg| arg.strip_prefix("-DCMAKE_SYSTEM_NAME="))
+                .map(String::from)
+                .collect::<Vec<_>>();
+            fs::remove_dir_all(&dir).unwrap();
+            names
         };
-        if ["i686", "x86_64", "thumbv7a", "aarch64"]
-            .iter()
-            .any(|t| target.contains(t))
-        {
-            base.to_string()
-        } else {
-            panic!("unsupported msvc target: {}", target);
-        }
+        assert_eq!(system_name("x86_64-pc-windows-gnu", None), ["Windows"]);
+        assert_eq!(system_name("x86_64-unknown-freebsd", None), ["FreeBSD"]);
+        assert!(system_name("aarch64-unknown-linux-gnu", None).is_empty());
+        assert_eq!(system_name("x86_64-pc-windows-gnu", Some("MSYS")), ["MSYS"]);
     }
 
-    fn defined(&self, var: &str) -> bool {
-        self.defines.iter().any(|&(ref a, _)| a == var)
+    #[test]
+    #[cfg(unix)]
+    fn toolchain_defines_come_first() {
+        let (dir, mut config) = fake_project("define-order");
+        config
+            .define("FOO", "1")
+            .define("CMAKE_SYSTEM_PROCESSOR", "aarch64")
+            .define("BAR", "2")
+            .define_typed("CMAKE_SYSROOT", "PATH", "/sysroot")
+            .define("BAZ", "3");
+        config.try_configure().unwrap();
+
+        let configure = &cmake_runs(&dir)[0];
+        let user = configure
+            .iter()
+            .filter(|arg| {
+                ["-DFOO", "-DBAR", "-DBAZ", "-DCMAKE_SYS"]
+                    .iter()
+                    .any(|p| arg.starts_with(p))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            user,
+            [
+                "-DCMAKE_SYSTEM_PROCESSOR=aarch64",
+                "-DCMAKE_SYSROOT:PATH=/sysroot",
+                "-DFOO=1",
+                "-DBAR=2",
+                "-DBAZ=3",
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
     }
 
-    // If a cmake project has previously been built (e.g. CMakeCache.txt already
-    // exists), then cmake will choke if the source directory for the original
-    // project being built has changed. Detect this situation through the
-    // `CMAKE_HOME_DIRECTORY` variable that cmake emits and if it doesn't match
-    // we blow away the build directory and start from scratch (the recommended
-    // solution apparently [1]).
-    //
-    // [1]: https://cmake.org/pipermail/cmake/2012-August/051545.html
-    fn maybe_clear(&self, dir: &Path) {
-        // CMake will apparently store canonicalized paths which normally
-        // isn't relevant to us but we canonicalize it here to ensure
-        // we're both checking the same thing.
-        let path = fs::canonicalize(&self.path).unwrap_or(self.path.clone());
-        let mut f = match File::open(dir.join("CMakeCache.txt")) {
-            Ok(f) => f,
-            Err(..) => return,
+    #[test]
+    #[cfg(unix)]
+    fn windows_link_search_dirs() {
+        let link_search = |target: &str| {
+            let (dir, mut config) = fake_project(&format!("link-search-{}", target));
+            fs::create_dir_all(dir.join("out/bin")).unwrap();
+            let dst = config.target(target).cargo_metadata(false).build();
+            let dirs = config
+                .metadata
+                .lock()
+                .unwrap()
+                .iter()
+                .filter_map(|line| line.strip_prefix("cargo:rustc-link-search=native="))
+                .map(PathBuf::from)
+                .collect::<Vec<_>>();
+            fs::remove_dir_all(&dir).unwrap();
+            (dst, dirs)
         };
-        let mut u8contents = Vec::new();
-        match f.read_to_end(&mut u8contents) {
-            Ok(f) => f,
-            Err(..) => return,
+
+        let (dst, dirs) = link_search("x86_64-pc-windows-gnu");
+        assert_eq!(dirs, [dst.join("lib"), dst.join("bin"), dst.clone()]);
+        let (dst, dirs) = link_search("x86_64-unknown-linux-gnu");
+        assert_eq!(dirs, [dst.join("lib")]);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn osx_architectures() {
+        let architectures = |archs: &[&str]| {
+            let (dir, mut config) = fake_project("osx-archs");
+            config.target("aarch64-apple-darwin").osx_archs(archs);
+            config.try_configure().unwrap();
+            let runs = cmake_runs(&dir);
+            fs::remove_dir_all(&dir).unwrap();
+            runs[0]
+                .iter()
+                .filter(|arg| arg.starts_with("-DCMAKE_OSX_ARCHITECTURES"))
+                .cloned()
+                .collect::<Vec<_>>()
         };
-        let contents = String::from_utf8_lossy(&u8contents);
-        drop(f);
-        for line in contents.lines() {
-            if line.starts_with("CMAKE_HOME_DIRECTORY") {
-                let needs_cleanup = match line.split('=').next_back() {
-                    Some(cmake_home) => fs::canonicalize(cmake_home)
-                        .ok()
-                        .map(|cmake_home| cmake_home != path)
-                        .unwrap_or(true),
-                    None => true,
-                };
-                if needs_cleanup {
-                    println!(
-                        "detected home dir change, cleaning out entire build \
-                         directory"
-                    );
-                    fs::remove_dir_all(dir).unwrap();
-                }
-                break;
-            }
+        assert_eq!(
+            architectures(&["arm64", "x86_64"]),
+            ["-DCMAKE_OSX_ARCHITECTURES=arm64;x86_64"]
+        );
+        assert_eq!(architectures(&[]), ["-DCMAKE_OSX_ARCHITECTURES=arm64"]);
+
+        let (dir, mut config) = fake_project("osx-archs-linux");
+        config.osx_archs(["arm64", "x86_64"]);
+        match config.try_configure() {
+            Err(Error::Config(msg)) => assert!(msg.contains("not supported"), "{}", msg),
+            result => panic!("unexpected result: {:?}", result),
         }
+        fs::remove_dir_all(&dir).unwrap();
     }
-}
 
-trait Target {
-    fn add_cmake_defines(&self, _cmd: &mut Command, _config: &Config) {}
+    #[test]
+    fn ios_defines() {
+        let sdk = |target: &str| AppleTarget::new(target).unwrap().sdk_name();
+        assert_eq!(sdk("aarch64-apple-ios").as_deref(), Some("iphoneos"));
+        assert_eq!(
+            sdk("aarch64-apple-ios-sim").as_deref(),
+            Some("iphonesimulator")
+        );
+        assert_eq!(sdk("x86_64-apple-ios").as_deref(), Some("iphonesimulator"));
+        assert_eq!(sdk("aarch64-apple-darwin").as_deref(), Some("macosx"));
 
-    fn should_exclude_env_var(&self, _key: &OsStr, _value: &OsStr) -> bool {
-        false
+        let mut config = Config::new("src");
+        config.osx_deployment_target("13.0");
+        let defines = AppleTarget::new("aarch64-apple-ios-sim")
+            .unwrap()
+            .cmake_defines(&config);
+        let value = |key: &str| {
+            defines
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.to_string_lossy().into_owned())
+        };
+        assert_eq!(
+            value("CMAKE_OSX_DEPLOYMENT_TARGET").as_deref(),
+            Some("13.0")
+        );
+        assert_eq!(value("CMAKE_SYSTEM_NAME").as_deref(), Some("iOS"));
+        assert_eq!(value("CMAKE_OSX_ARCHITECTURES").as_deref(), Some("arm64"));
+        assert!(value("CMAKE_OSX_SYSROOT").is_some());
+
+        config
+            .define("CMAKE_OSX_SYSROOT", "/sdk")
+            .define("CMAKE_SYSTEM_NAME", "tvOS");
+        let defines = AppleTarget::new("aarch64-apple-ios")
+            .unwrap()
+            .cmake_defines(&config);
+        assert!(defines
+            .iter()
+            .all(|(k, _)| k != "CMAKE_OSX_SYSROOT" && k != "CMAKE_SYSTEM_NAME"));
     }
 
-    fn filter_compiler_args(&self, _flags: &mut OsString) {}
-}
+    #[test]
+    #[cfg(unix)]
+    fn clean_first_rebuilds() {
+        let (dir, mut config) = fake_project("clean-first");
+        config.try_build().unwrap();
+        config.clean_first(true).try_build().unwrap();
 
-fn get_target(target_triple: &str) -> Box<dyn Target> {
-    let target: Option<Box<dyn Target>>;
-    target = AppleTarget::new(target_triple)
-        .map(|apple_target| Box::new(apple_target) as Box<dyn Target>);
-    target.unwrap_or_else(|| Box::new(GenericTarget::new(target_triple)))
-}
+        let runs = cmake_runs(&dir);
+        let builds = runs
+            .iter()
+            .filter(|run| run.iter().any(|arg| arg == "--build"))
+            .collect::<Vec<_>>();
+        assert_eq!(builds.len(), 2, "{:?}", runs);
+        assert!(!builds[0].iter().any(|arg| arg == "--clean-first"));
+        assert!(builds[1].iter().any(|arg| arg == "--clean-first"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
-struct GenericTarget {}
+    #[test]
+    #[cfg(unix)]
+    fn make_program_define() {
+        let (dir, mut config) = fake_project("make-program");
+        config.try_configure().unwrap();
+        let ninja = dir.join("cmake");
+        config
+            .generator("Ninja")
+            .make_program(&ninja)
+            .try_configure()
+            .unwrap();
 
-impl GenericTarget {
-    fn new(_target_triple: &str) -> GenericTarget {
-        GenericTarget {}
+        let runs = cmake_runs(&dir);
+        let make_program = |run: &[String]| {
+            run.iter()
+                .filter(|arg| arg.starts_with("-DCMAKE_MAKE_PROGRAM"))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        assert!(make_program(&runs[0]).is_empty(), "{:?}", runs[0]);
+        assert_eq!(
+            make_program(&runs[1]),
+            [format!("-DCMAKE_MAKE_PROGRAM={}", ninja.display())]
+        );
+        fs::remove_dir_all(&dir).unwrap();
     }
-}
 
-impl Target for GenericTarget {}
+    #[test]
+    #[cfg(unix)]
+    fn configure_arg_is_configure_only() {
+        let (dir, mut config) = fake_project("configure-arg");
+        config.define("FOO", "1").configure_arg("-Wno-dev");
+        config.try_build().unwrap();
 
-struct AppleTarget {
-    rust_target: String,
-    rust_target_arch: String,
-    rust_target_platform: String,
-}
+        let runs = cmake_runs(&dir);
+        let configure = &runs[0];
+        let pos = |arg: &str| configure.iter().position(|a| a == arg);
+        assert!(pos("-Wno-dev").unwrap() > pos("-DFOO=1").unwrap());
+        assert!(runs[1..].iter().flatten().all(|arg| arg != "-Wno-dev"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
-impl AppleTarget {
-    fn new(target_triple: &str) -> Option<AppleTarget> {
-        let parts: Vec<&str> = target_triple.split('-').collect();
-        if parts.len() < 3 {
-            return None;
-        }
+    #[test]
+    #[cfg(unix)]
+    fn out_dir_with_space() {
+        let (dir, mut config) = fake_project("out-dir-space");
+        let out = dir.join("out dir");
+        let dst = config.out_dir(&out).cargo_metadata(false).build();
 
-        let rust_target_arch = parts[0];
-        let rust_target_vendor = parts[1];
-        let rust_target_platform = parts[2];
+        assert_eq!(dst, out);
+        let root = format!("cargo:root={}", out.display());
+        assert!(config.metadata.lock().unwrap().contains(&root));
+        let prefix = format!("-DCMAKE_INSTALL_PREFIX={}", out.display());
+        assert!(cmake_runs(&dir)[0].contains(&prefix));
+        assert!(out.join("build").is_dir());
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
-        if rust_target_vendor != "apple" {
-            return None;
-        }
-        if rust_target_platform != "ios" && !rust_target_platform.starts_with("darwin") {
-            eprintln!(
-                "Warning: unknown Apple platform ({}) for target: {}",
-                rust_target_platform, target_triple
-            );
-            return None;
+    #[test]
+    fn host_and_target_getters() {
+        let mut config = Config::new("src");
+        {
+            let mut env = config.env_cache.lock().unwrap();
+            env.insert("TARGET".to_string(), Some("aarch64-linux-android".into()));
+            env.insert("HOST".to_string(), Some("x86_64-unknown-linux-gnu".into()));
         }
+        assert_eq!(config.get_target(), "aarch64-linux-android");
+        assert_eq!(config.get_host(), "x86_64-unknown-linux-gnu");
 
-        Some(AppleTarget {
-            rust_target: target_triple.to_owned(),
-            rust_target_arch: rust_target_arch.to_owned(),
-            rust_target_platform: rust_target_platform.to_owned(),
-        })
+        config
+            .target("x86_64-pc-windows-gnu")
+            .host("aarch64-apple-darwin");
+        assert_eq!(config.get_target(), "x86_64-pc-windows-gnu");
+        assert_eq!(config.get_host(), "aarch64-apple-darwin");
     }
 
-    fn is_ios_target(&self) -> bool {
-        self.rust_target_platform == "ios"
-    }
+    #[test]
+    #[cfg(unix)]
+    fn undefine_removes_defines() {
+        let (dir, mut config) = fake_project("undefine");
+        config
+            .define("FOO", "1")
+            .define("BAR", "x")
+            .define("FOO", "2")
+            .undefine("BAR")
+            .undefine("QUX_*")
+            .define("QUX_A", "y");
+        config.try_configure().unwrap();
 
-    fn is_osx_target(&self) -> bool {
-        self.rust_target_platform.starts_with("darwin")
+        let configure = &cmake_runs(&dir)[0];
+        let pos = |arg: &str| configure.iter().position(|a| a == arg).unwrap();
+        let defines = configure
+            .iter()
+            .filter(|arg| {
+                ["-DFOO", "-DBAR", "-DQUX"]
+                    .iter()
+                    .any(|p| arg.starts_with(p))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(defines, ["-DFOO=2", "-DQUX_A=y"]);
+        let first_define = configure
+            .iter()
+            .position(|arg| arg.starts_with("-D"))
+            .unwrap();
+        assert!(pos("-UBAR") < pos("-UQUX_*"));
+        assert!(pos("-UQUX_*") < first_define);
+        fs::remove_dir_all(&dir).unwrap();
     }
 
-    fn cmake_target_arch(&self) -> Option<String> {
-        match self.rust_target_arch.as_str() {
-            "aarch64" => Some("arm64".to_owned()),
-            "armv7" => Some("armv7".to_owned()),
-            "armv7s" => Some("armv7s".to_owned()),
-            "i386" => Some("i386".to_owned()),
-            "x86_64" => Some("x86_64".to_owned()),
-            _ => {
-                eprintln!(
-                    "Warning: unknown architecture for target: {}",
-                    self.rust_target_arch
-                );
-                None
-            }
-        }
-    }
+    #[test]
+    #[cfg(unix)]
+    fn build_artifacts_inspect_the_install_tree() {
+        let (dir, mut config) = fake_project("artifacts");
+        let out = dir.join("out");
+        fs::create_dir_all(out.join("include")).unwrap();
+        fs::create_dir_all(out.join("lib")).unwrap();
+        fs::write(out.join("lib/libfoo.a"), "").unwrap();
+        fs::write(out.join("lib/libbar.so"), "").unwrap();
+        fs::create_dir_all(out.join("build")).unwrap();
+        fs::write(
+            out.join("build/install_manifest.txt"),
+            format!("{}\n", out.join("lib/libfoo.a").display()),
+        )
+        .unwrap();
+        let artifacts = config.cargo_metadata(false).build_artifacts();
 
-    fn sdk_name(&self) -> Option<String> {
-        if self.is_ios_target() {
-            match self.rust_target_arch.as_str() {
-                "aarch64" | "armv7" | "armv7s" => Some("iphoneos".to_owned()),
-                "i386" | "x86_64" => Some("iphonesimulator".to_owned()),
-                _ => {
-                    eprintln!(
-                        "Warning: unknown architecture for Apple target: {}",
-                        self.rust_target_arch
-                    );
-                    None
-                }
-            }
-        } else if self.is_osx_target() {
-            Some("macosx".to_owned())
-        } else {
-            eprintln!(
-                "Warning: could not determine sdk name for Apple target: {}",
-                self.rust_target
-            );
-            None
-        }
+        assert_eq!(artifacts.install_prefix, out);
+        assert_eq!(artifacts.build_dir, out.join("build"));
+        assert_eq!(artifacts.include_dir, out.join("include"));
+        assert_eq!(artifacts.lib_dirs, [out.join("lib")]);
+        let mut libs = artifacts.libs.clone();
+        libs.sort();
+        assert_eq!(libs, ["dylib=bar", "static=foo"]);
+        assert_eq!(artifacts.installed_files, [out.join("lib/libfoo.a")]);
+        assert!(artifacts
+            .metadata
+            .contains(&format!("cargo:root={}", out.display())));
+        fs::remove_dir_all(&dir).unwrap();
     }
 
-    fn deployment_target(&self) -> Option<String> {
-        if self.is_ios_target() {
-            println!("cargo:rerun-if-env-changed=IPHONEOS_DEPLOYMENT_TARGET");
-            Some(std::env::var("IPHONEOS_DEPLOYMENT_TARGET").unwrap_or_else(|_| "7.0".into()))
-        } else if self.is_osx_target() {
-            println!("cargo:rerun-if-env-changed=MACOSX_DEPLOYMENT_TARGET");
-            Some(std::env::var("MACOSX_DEPLOYMENT_TARGET").unwrap_or_else(|_| "".into()))
-        } else {
-            eprintln!(
-                "Warning: could not determine deployment target for Apple target: {}",
-                self.rust_target
-            );
-            None
-        }
+    #[test]
+    #[cfg(unix)]
+    fn msvc_ninja_from_env_passes_compilers() {
+        let compilers = |generator: &str| {
+            let (dir, mut config) = fake_project("msvc-ninja");
+            config
+                .target("x86_64-pc-windows-msvc")
+                .host("x86_64-pc-windows-msvc")
+                .make_program(dir.join("cmake"));
+            config
+                .env_cache
+                .lock()
+                .unwrap()
+                .insert("CMAKE_GENERATOR".to_string(), Some(generator.into()));
+            config.try_configure().unwrap();
+            let runs = cmake_runs(&dir);
+            fs::remove_dir_all(&dir).unwrap();
+            runs[0]
+                .iter()
+                .filter_map(|arg| arg.split('=').next())
+                .filter(|key| key.starts_with("-DCMAKE_") && key.ends_with("_COMPILER"))
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            compilers("Ninja"),
+            [
+                "-DCMAKE_C_COMPILER",
+                "-DCMAKE_CXX_COMPILER",
+                "-DCMAKE_ASM_COMPILER"
+            ]
+        );
+        assert!(compilers("Unix Makefiles").is_empty());
     }
-}
 
-impl Target for AppleTarget {
-    fn add_cmake_defines(&self, cmd: &mut Command, config: &Config) {
-        // These 3 CMAKE_OSX_* variables apply to all Apple platforms
+    #[test]
+    #[cfg(unix)]
+    fn preset_build_and_install() {
+        let build = |presets: &str| {
+            let (dir, mut config) = fake_project("preset");
+            fs::write(dir.join("src/CMakePresets.json"), presets).unwrap();
+            config
+                .preset("ci")
+                .cargo_metadata(false)
+                .try_build()
+                .unwrap();
+            let runs = cmake_runs(&dir);
+            let warned = config
+                .metadata
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|line| line.contains("not using build preset"));
+            fs::remove_dir_all(&dir).unwrap();
+            (runs, warned)
+        };
+        let has = |run: &[String], args: &[&str]| run.windows(args.len()).any(|w| w == args);
 
-        if !config.defined("CMAKE_OSX_ARCHITECTURES") {
-            if let Some(cmake_target_arch) = self.cmake_target_arch() {
-                cmd.arg(format!("-DCMAKE_OSX_ARCHITECTURES={}", cmake_target_arch));
-            }
-        }
+        let (runs, warned) = build(
+            r#"{
+                "version": 3,
+                "configurePresets": [
+                    {"name": "base", "generator": "Ninja"},
+                    {"name": "ci", "inherits": ["base"], "cacheVariables": {"X": "1"}}
+                ],
+                "buildPresets": [
+                    {"name": "common", "configuration": "RelWithDebInfo", "jobs": 2},
+                    {"name": "ci", "inherits": "common", "configurePreset": "ci"}
+                ]
+            }"#,
+        );
+        assert!(!warned);
+        assert!(has(&runs[0], &["--preset", "ci"]), "{:?}", runs[0]);
+        assert!(has(&runs[1], &["--build"]) && has(&runs[1], &["--preset", "ci"]));
+        assert!(
+            !runs[1].iter().any(|arg| arg == "--config"),
+            "{:?}",
+            runs[1]
+        );
+        assert!(has(&runs[2], &["--install"]) && has(&runs[2], &["--config", "RelWithDebInfo"]));
 
-        if !config.defined("CMAKE_OSX_SYSROOT") {
-            if let Some(sdk_name) = self.sdk_name() {
-                cmd.arg(format!("-DCMAKE_OSX_SYSROOT={}", sdk_name));
-            }
+        let (runs, warned) = build(
+            r#"{"version": 3, "configurePresets": [{"name": "ci", "binaryDir": "out"}],
+                "buildPresets": [{"name": "ci", "configurePreset": "ci"}]}"#,
+        );
+        assert!(warned);
+        for run in &runs[1..] {
+            assert!(
+                !run.iter().any(|arg| arg == "--config" || arg == "--preset"),
+                "{:?}",
+                run
+            );
         }
+    }
 
-        if !config.defined("CMAKE_OSX_DEPLOYMENT_TARGET") {
-            if let Some(deployment_target) = self.deployment_target() {
-                cmd.arg(format!(
-                    "-DCMAKE_OSX_DEPLOYMENT_TARGET={}",
-                    deployment_target
-                ));
+    #[test]
+    fn json_parsing() {
+        let (json, rest) =
+            parse_json(r#" {"a": ["x\"y", 1, true, null, {}], "b": "\u00e9\\"} tail"#).unwrap();
+        assert_eq!(rest, " tail");
+        match json.get("a") {
+            Some(Json::Array(elements)) => {
+                assert_eq!(elements.len(), 5);
+                assert_eq!(elements[0].as_str(), Some("x\"y"));
             }
+            _ => panic!("expected an array"),
         }
-
-        // CMAKE_SYSTEM_NAME is used to tell cmake we're cross-compiling
-        if self.is_ios_target() && !config.defined("CMAKE_SYSTEM_NAME") {
-            cmd.arg("-DCMAKE_SYSTEM_NAME=iOS");
-        }
+        assert_eq!(json.get("b").and_then(Json::as_str), Some("\u{e9}\\"));
+        assert!(parse_json(r#"{"a": [1, 2}"#).is_none());
+        assert!(parse_json(r#"{"a" 1}"#).is_none());
     }
 
-    fn should_exclude_env_var(&self, key: &OsStr, _value: &OsStr) -> bool {
-        key.to_str().map_or(false, |key| {
-            // These cause issues with llvm if an env var for a different Apple platform
-            // is present. Since cmake handles communicating these values to llvm, and
-            // we use cmake defines to tell cmake what the value is, the env vars themselves
-            // are filtered out.
-            key.ends_with("DEPLOYMENT_TARGET") || key.starts_with("SDK")
-        })
+    #[test]
+    #[cfg(unix)]
+    fn existing_build_dir_is_left_alone() {
+        let (dir, mut config) = fake_project("existing-build-dir");
+        let existing = dir.join("existing");
+        fs::create_dir_all(&existing).unwrap();
+        let cache = "CMAKE_GENERATOR:INTERNAL=Unix Makefiles\n";
+        fs::write(existing.join("CMakeCache.txt"), cache).unwrap();
+        // Neither the generator, which would need `ninja`, nor the settings
+        // that write to the build directory apply to it.
+        config
+            .env_cache
+            .lock()
+            .unwrap()
+            .insert("CMAKE_GENERATOR".to_string(), Some("Ninja".into()));
+        config
+            .use_existing_build_dir(&existing)
+            .define_force("FOO", "1")
+            .build_all_including_excluded();
+        config.try_build().unwrap();
+
+        let runs = cmake_runs(&dir);
+        assert!(
+            runs.iter()
+                .all(|run| run[0] == "--build" || run[0] == "--install"),
+            "{:?}",
+            runs
+        );
+        let entries = fs::read_dir(&existing)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(entries, ["CMakeCache.txt"]);
+        assert_eq!(
+            fs::read_to_string(existing.join("CMakeCache.txt")).unwrap(),
+            cache
+        );
+        fs::remove_dir_all(&dir).unwrap();
     }
 
-    fn filter_compiler_args(&self, flags: &mut OsString) {
-        if let Some(flags_str) = flags.to_str() {
-            lazy_static! {
-                static ref ARCH_REGEX: Regex = Regex::new("-arch [^ ]+ ").unwrap();
-                static ref DEPLOYMENT_TARGET_REGEX: Regex =
-                    Regex::new("-m[\\w-]+-version-min=[\\d.]+ ").unwrap();
-                static ref SYSROOT_REGEX: Regex = Regex::new("-isysroot [^ ]+ ").unwrap();
-            }
+    #[test]
+    #[cfg(unix)]
+    fn env_flags_and_cmake_args() {
+        // cc reads `CFLAGS` and `CXXFLAGS` from the process environment; the
+        // variables for a target no other test builds keep this test apart.
+        env::set_var("CFLAGS_riscv64gc_unknown_linux_gnu", "-DENV_C");
+        env::set_var("CXXFLAGS_riscv64gc_unknown_linux_gnu", "-DENV_CXX");
+        let (dir, mut config) = fake_project("env-flags");
+        config
+            .target("riscv64gc-unknown-linux-gnu")
+            .cflag("-DUSER_C")
+            .cxxflag("-DUSER_CXX")
+            .define("FOO", "1");
+        config.env_cache.lock().unwrap().insert(
+            "CMAKE_ARGS".to_string(),
+            Some(r#"-DFROM_ARGS="a b" -Wno-dev"#.into()),
+        );
+        config.try_configure().unwrap();
 
-            let mut flags_string = flags_str.to_owned();
-            flags_string.push(' ');
+        let configure = &cmake_runs(&dir)[0];
+        let flags = |var: &str| {
+            let prefix = format!("-D{}=", var);
+            let value = configure
+                .iter()
+                .find_map(|arg| arg.strip_prefix(&prefix))
+                .unwrap_or_else(|| panic!("no {} in {:?}", var, configure));
+            value
+                .split_whitespace()
+                .filter(|flag| flag.starts_with("-DENV_") || flag.starts_with("-DUSER_"))
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(flags("CMAKE_C_FLAGS"), ["-DENV_C", "-DUSER_C"]);
+        assert_eq!(flags("CMAKE_CXX_FLAGS"), ["-DENV_CXX", "-DUSER_CXX"]);
 
-            // These are set by cmake
-            flags_string = ARCH_REGEX.replace(&flags_string, "").into_owned();
-            flags_string = DEPLOYMENT_TARGET_REGEX
-                .replace(&flags_string, "")
-                .into_owned();
-            flags_string = SYSROOT_REGEX.replace(&flags_string, "").into_owned();
+        let pos = |arg: &str| configure.iter().position(|a| a == arg).unwrap();
+        assert!(pos("-DFOO=1") < pos("-DFROM_ARGS=a b"));
+        assert_eq!(pos("-DFROM_ARGS=a b") + 1, pos("-Wno-dev"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
-            if flags_string.ends_with(' ') {
-                flags_string.pop();
-            }
+    #[test]
+    #[cfg(unix)]
+    fn bool_and_typed_define_text() {
+        let (dir, mut config) = fake_project("typed-defines");
+        config
+            .define_bool("X", true)
+            .define_bool("Y", false)
+            .define_typed("Z", "PATH", "/opt/z dir")
+            .define_typed("W", "STRING", "a;b");
+        config.try_configure().unwrap();
 
-            flags.clear();
-            flags.push(OsString::from(flags_string));
-        }
+        let configure = &cmake_runs(&dir)[0];
+        let defines = configure
+            .iter()
+            .filter(|arg| {
+                ["-DX", "-DY", "-DZ", "-DW"]
+                    .iter()
+                    .any(|p| arg.starts_with(p))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            defines,
+            ["-DX=ON", "-DY=OFF", "-DZ:PATH=/opt/z dir", "-DW:STRING=a;b"]
+        );
+        fs::remove_dir_all(&dir).unwrap();
     }
-}
 
-fn run(cmd: &mut Command, program: &str) {
-    println!("running: {:?}", cmd);
-    let status = match cmd.status() {
-        Ok(status) => status,
-        Err(ref e) if e.kind() == ErrorKind::NotFound => {
-            fail(&format!(
-                "failed to execute command: {}\nis `{}` not installed?",
-                e, program
-            ));
+    #[test]
+    #[cfg(unix)]
+    fn env_reruns_include_earlier_lookups() {
+        let (dir, mut config) = fake_project("env-reruns");
+        config
+            .map_feature("foo-bar", "WITH_FOO")
+            .register_rerun_if_changed(true)
+            .cargo_metadata(false);
+        config.try_configure().unwrap();
+
+        let metadata = config.metadata.lock().unwrap();
+        let reruns = metadata
+            .iter()
+            .filter_map(|line| line.strip_prefix("cargo:rerun-if-env-changed="))
+            .collect::<Vec<_>>();
+        for var in ["CARGO_FEATURE_FOO_BAR", "CMAKE", "CMAKE_GENERATOR"] {
+            assert_eq!(
+                reruns.iter().filter(|v| **v == var).count(),
+                1,
+                "{:?}",
+                reruns
+            );
         }
-        Err(e) => fail(&format!("failed to execute command: {}", e)),
-    };
-    if !status.success() {
-        fail(&format!(
-            "command did not execute successfully, got: {}",
-            status
-        ));
+        assert!(!reruns.contains(&"PATH"), "{:?}", reruns);
+        drop(metadata);
+        fs::remove_dir_all(&dir).unwrap();
     }
-}
 
-fn find_exe(path: &Path) -> PathBuf {
-    env::split_paths(&env::var_os("PATH").unwrap_or(OsString::new()))
-        .map(|p| p.join(path))
-        .find(|p| fs::metadata(p).is_ok())
-        .unwrap_or(path.to_owned())
-}
+    #[test]
+    #[cfg(unix)]
+    fn rerun_skips_cargo_target_dir() {
+        let (dir, mut config) = fake_project("rerun-target-dir");
+        let src = dir.join("src");
+        let out_dir = src.join("target/debug/build/foo-1234/out");
+        fs::create_dir_all(&out_dir).unwrap();
+        fs::write(src.join("target/debug/libfoo.rlib"), "").unwrap();
+        fs::create_dir_all(src.join("custom-target")).unwrap();
+        fs::write(src.join("custom-target/libbar.rlib"), "").unwrap();
+        {
+            let mut env = config.env_cache.lock().unwrap();
+            env.insert("OUT_DIR".to_string(), Some(out_dir.into()));
+            env.insert(
+                "CARGO_TARGET_DIR".to_string(),
+                Some(src.join("custom-target").into()),
+            );
+        }
+        config.register_rerun_if_changed(true).cargo_metadata(false);
+        config.try_configure().unwrap();
 
-fn getenv_unwrap(v: &str) -> String {
-    match env::var(v) {
-        Ok(s) => s,
-        Err(..) => fail(&format!("environment variable `{}` not defined", v)),
+        let metadata = config.metadata.lock().unwrap();
+        let files = metadata
+            .iter()
+            .filter_map(|line| line.strip_prefix("cargo:rerun-if-changed="))
+            .collect::<Vec<_>>();
+        assert_eq!(files, [src.join("CMakeLists.txt").to_str().unwrap()]);
+        drop(metadata);
+        fs::remove_dir_all(&dir).unwrap();
     }
-}
 
-fn fail(s: &str) -> ! {
-    panic!("\n{}\n\nbuild script failed, must exit now", s)
+    #[test]
+    fn define_limits() {
+        let defines = |n: usize, len: usize| {
+            (0..n)
+                .map(|_| OsString::from("x".repeat(len)))
+                .collect::<Vec<_>>()
+        };
+        assert!(!exceeds_define_limits(&[]));
+        assert!(!exceeds_define_limits(&defines(100, 10)));
+        assert!(exceeds_define_limits(&defines(101, 10)));
+        assert!(!exceeds_define_limits(&defines(2, 4096)));
+        assert!(exceeds_define_limits(&defines(2, 4097)));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn many_defines_go_through_cache_file() {
+        let (dir, mut config) = fake_project("many-defines");
+        for i in 0..2000 {
+            config.define(format!("OPTION_{}", i), "ON");
+        }
+        // Automatic on Windows only, so forced here.
+        config.defines_via_cache_file(true);
+        config.try_configure().unwrap();
+
+        let args = fs::read_to_string(dir.join("args")).unwrap();
+        assert!(!args.lines().any(|arg| arg.starts_with("-DOPTION_")));
+        let script = args
+            .lines()
+            .skip_while(|arg| *arg != "-C")
+            .nth(1)
+            .expect("no -C argument");
+        let script = fs::read_to_string(script).unwrap();
+        for i in 0..2000 {
+            let set = format!("set(OPTION_{} \"ON\" CACHE STRING \"\" FORCE)\n", i);
+            assert!(script.contains(&set), "missing {}", set);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }